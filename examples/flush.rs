@@ -0,0 +1,47 @@
+// Uploads a file's pending changes without waiting for it to be closed:
+//
+//     cargo run --example flush -- /mnt/discord/file.txt
+use std::env;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::process;
+
+// Must match IOCTL_FLUSH in src/main.rs
+const IOCTL_FLUSH: u32 = 0x4401;
+
+fn main() {
+    let paths: Vec<String> = env::args().skip(1).collect();
+
+    if paths.is_empty() {
+        eprintln!("usage: flush FILE...");
+        process::exit(2);
+    }
+
+    let mut failed = false;
+
+    for path in &paths {
+        match flush(path) {
+            Ok(written) => println!("{}: {} chunk(s) written", path, written),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn flush(path: &str) -> io::Result<i32> {
+    let file = File::open(path)?;
+    let written = unsafe { libc::ioctl(file.as_raw_fd(), IOCTL_FLUSH as _) };
+
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(written)
+}
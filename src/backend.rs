@@ -0,0 +1,446 @@
+use libc::{c_int, EACCES, EDQUOT, EFBIG, EIO, ENOENT, ENOSPC};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::SystemTime;
+
+pub type ChunkId = u64;
+
+pub trait StorageBackend: Send + Sync {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId>;
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>>;
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()>;
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>>;
+    fn save_index(&self, index: &[u8]) -> io::Result<()>;
+
+    // Saves `index` only while the stored one is still `expected`, in one step
+    // so no other save can land in between. Returns whether it was saved.
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool>;
+
+    // Every stored chunk along with when it was last written
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>>;
+
+    // The generation replaced by the last save_index, if the backend keeps one
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    // Tells this store apart from every other one, chunk ids only mean something
+    // within a store. Without one nothing kept locally can be trusted after a restart.
+    fn store_id(&self) -> Option<String> {
+        None
+    }
+
+    // The bytes of a chunk within `range`, cut short at its end. Backends that
+    // can't fetch part of a chunk download all of it and drop the rest.
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let chunk = self.get_chunk(id)?;
+        let range = clamp(range, chunk.len());
+
+        Ok(chunk[range].to_vec())
+    }
+}
+
+impl<B: StorageBackend + ?Sized> StorageBackend for Box<B> {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        (**self).put_chunk(data)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        (**self).get_chunk(id)
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        (**self).delete_chunk(id)
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        (**self).load_index()
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        (**self).save_index(index)
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        (**self).save_index_if(expected, index)
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        (**self).list_chunks()
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        (**self).load_previous_index()
+    }
+
+    fn store_id(&self) -> Option<String> {
+        (**self).store_id()
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        (**self).get_chunk_range(id, range)
+    }
+}
+
+// Turns a backend failure into the errno the kernel hands back to callers.
+// Errors that came straight from the OS already carry one.
+pub fn errno(e: &io::Error) -> c_int {
+    if let Some(errno) = e.raw_os_error() {
+        return errno;
+    }
+
+    match e.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::FileTooLarge => EFBIG,
+        ErrorKind::QuotaExceeded => EDQUOT,
+        ErrorKind::StorageFull => ENOSPC,
+        _ => EIO,
+    }
+}
+
+// Cuts `range` down to what a chunk of `len` bytes holds
+pub fn clamp(range: Range<usize>, len: usize) -> Range<usize> {
+    let end = range.end.min(len);
+    range.start.min(end)..end
+}
+
+// FNV-1a. Unlike the std hashers it's stable across builds, so it can be persisted.
+pub fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+static STORES: AtomicU64 = AtomicU64::new(0);
+
+// Unique enough to tell stores apart, without pulling in a random number generator
+fn new_store_id() -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut seed = now.as_nanos().to_le_bytes().to_vec();
+    seed.extend_from_slice(&std::process::id().to_le_bytes());
+    seed.extend_from_slice(&STORES.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+
+    format!("{:016x}{:08x}", checksum(&seed), std::process::id())
+}
+
+// Runs `op` over `items` on up to `concurrency` threads, keeping the results in order
+pub fn parallel<T: Sync, R: Send>(
+    items: &[T],
+    concurrency: usize,
+    op: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<_>>());
+
+    thread::scope(|scope| {
+        for _ in 0..concurrency.min(items.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+
+                let result = op(item);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect()
+}
+
+pub struct MemBackend {
+    chunks: Mutex<HashMap<ChunkId, (Vec<u8>, SystemTime)>>,
+    index: Mutex<Option<Vec<u8>>>,
+    last_id: AtomicU64,
+    id: String,
+}
+
+// Every instance is a store of its own, gone with it
+impl Default for MemBackend {
+    fn default() -> Self {
+        MemBackend {
+            chunks: Mutex::default(),
+            index: Mutex::default(),
+            last_id: AtomicU64::default(),
+            id: new_store_id(),
+        }
+    }
+}
+
+impl MemBackend {
+    #[cfg(test)]
+    pub fn overwrite_chunk(&self, id: ChunkId, data: &[u8]) {
+        if let Some(chunk) = self.chunks.lock().unwrap().get_mut(&id) {
+            *chunk = (data.to_vec(), SystemTime::now());
+        }
+    }
+}
+
+impl StorageBackend for MemBackend {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.chunks
+            .lock()
+            .unwrap()
+            .insert(id, (data.to_vec(), SystemTime::now()));
+
+        Ok(id)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        let chunks = self.chunks.lock().unwrap();
+
+        chunks
+            .get(&id)
+            .map(|(data, _)| data.clone())
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no chunk {}", id)))
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        self.chunks.lock().unwrap().remove(&id);
+
+        Ok(())
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.index.lock().unwrap().clone())
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        *self.index.lock().unwrap() = Some(index.to_vec());
+
+        Ok(())
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        let mut stored = self.index.lock().unwrap();
+
+        if stored.as_deref() != expected {
+            return Ok(false);
+        }
+
+        *stored = Some(index.to_vec());
+
+        Ok(true)
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        let chunks = self.chunks.lock().unwrap();
+
+        Ok(chunks.iter().map(|(id, (_, time))| (*id, *time)).collect())
+    }
+
+    fn store_id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let chunks = self.chunks.lock().unwrap();
+
+        chunks
+            .get(&id)
+            .map(|(data, _)| data[clamp(range, data.len())].to_vec())
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no chunk {}", id)))
+    }
+}
+
+// Keeps every chunk as a file under `<root>/chunks`, named by its id
+pub struct DirBackend {
+    root: PathBuf,
+    last_id: AtomicU64,
+    id: String,
+}
+
+impl DirBackend {
+    pub fn open(root: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(root.join("chunks"))?;
+
+        let mut last_id = 0;
+
+        for entry in fs::read_dir(root.join("chunks"))? {
+            if let Some(id) = entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+                last_id = last_id.max(id);
+            }
+        }
+
+        // Made up when the store is, so a new store in the same place is another one
+        let id = match read_optional(&root.join("store-id"))? {
+            Some(id) => String::from_utf8_lossy(&id).into_owned(),
+            None => {
+                let id = new_store_id();
+                fs::write(root.join("store-id"), &id)?;
+                id
+            }
+        };
+
+        Ok(DirBackend {
+            root,
+            last_id: AtomicU64::new(last_id),
+            id,
+        })
+    }
+
+    fn chunk_path(&self, id: ChunkId) -> PathBuf {
+        self.root.join("chunks").join(id.to_string())
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.root.join("index.json")
+    }
+
+    fn previous_index_path(&self) -> PathBuf {
+        self.root.join("index.json.prev")
+    }
+
+    // Held while the index is swapped, so processes sharing the store take turns.
+    // The lock goes with the file when it's dropped.
+    fn lock_index(&self) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.root.join("index.lock"))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(file)
+    }
+
+    // The new index is written and verified next to the current one, which is
+    // kept as the previous generation before the new one is renamed into place.
+    // A crash at any point leaves at least one intact generation behind.
+    fn swap_index(&self, index: &[u8]) -> io::Result<()> {
+        let tmp = self.root.join("index.json.tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(index)?;
+        file.sync_all()?;
+
+        if fs::read(&tmp)? != index {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "index did not read back intact",
+            ));
+        }
+
+        if self.index_path().exists() {
+            fs::rename(self.index_path(), self.previous_index_path())?;
+        }
+
+        fs::rename(tmp, self.index_path())
+    }
+}
+
+fn read_optional(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl StorageBackend for DirBackend {
+    // Other processes may share the store and hand out the same ids, so an id
+    // is only ours once its file is created, and a taken one means trying the next
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        loop {
+            let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+            let created = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.chunk_path(id));
+
+            match created {
+                Ok(mut file) => {
+                    file.write_all(data)?;
+                    return Ok(id);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        fs::read(self.chunk_path(id))
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        fs::remove_file(self.chunk_path(id))
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        read_optional(&self.index_path())
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        let _lock = self.lock_index()?;
+        self.swap_index(index)
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        let _lock = self.lock_index()?;
+
+        if read_optional(&self.index_path())?.as_deref() != expected {
+            return Ok(false);
+        }
+
+        self.swap_index(index)?;
+
+        Ok(true)
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        read_optional(&self.previous_index_path())
+    }
+
+    fn store_id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        let mut chunks = Vec::new();
+
+        for entry in fs::read_dir(self.root.join("chunks"))? {
+            let entry = entry?;
+
+            // Skips anything that isn't a chunk, like temporary files of older versions
+            if let Some(id) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                chunks.push((id, entry.metadata()?.modified()?));
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    // Reads only the range off the file, like a ranged request would
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.chunk_path(id))?;
+        file.seek(SeekFrom::Start(range.start as u64))?;
+
+        let mut data = Vec::with_capacity(range.len());
+        file.take(range.len() as u64).read_to_end(&mut data)?;
+
+        Ok(data)
+    }
+}
@@ -0,0 +1,194 @@
+use crate::backend::{self, ChunkId, StorageBackend};
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::process;
+use std::time::{Duration, Instant};
+
+// What one file size measured, over `count` files
+pub struct Run {
+    pub size: usize,
+    pub count: usize,
+    pub writes: Timings,
+    pub reads: Timings,
+}
+
+// Per-file latencies, kept whole since a bench only takes a few of them
+pub struct Timings {
+    latencies: Vec<Duration>,
+    bytes: u64,
+}
+
+impl Timings {
+    fn new(size: usize, mut latencies: Vec<Duration>) -> Self {
+        let bytes = (size * latencies.len()) as u64;
+        latencies.sort();
+
+        Timings { latencies, bytes }
+    }
+
+    // Bytes per second over the time spent, files being moved one after another
+    pub fn throughput(&self) -> f64 {
+        let total: Duration = self.latencies.iter().sum();
+
+        self.bytes as f64 / total.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    // The nearest-rank quantile
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = ((self.latencies.len() as f64 * q).ceil() as usize).max(1);
+
+        self.latencies
+            .get(rank - 1)
+            .copied()
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} MiB/s, p50 {:?}, p99 {:?}",
+            self.throughput() / (1 << 20) as f64,
+            self.quantile(0.5),
+            self.quantile(0.99)
+        )
+    }
+}
+
+impl fmt::Display for Run {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} byte(s) x {}: write {}, read {}",
+            self.size, self.count, self.writes, self.reads
+        )
+    }
+}
+
+pub fn run<B: StorageBackend + ?Sized>(
+    backend: &B,
+    sizes: &[usize],
+    count: usize,
+    chunk_size: usize,
+    concurrency: usize,
+) {
+    match measure(backend, sizes, count, chunk_size, concurrency) {
+        Ok(runs) => runs.iter().for_each(|run| println!("{}", run)),
+        Err(e) => {
+            eprintln!("bench failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Writes `count` files of each size, split into chunks the way flushes split
+// them, then reads them back and deletes them. Every file is checked against
+// what was written, so a store that loses data can't pass as a fast one.
+fn measure<B: StorageBackend + ?Sized>(
+    backend: &B,
+    sizes: &[usize],
+    count: usize,
+    chunk_size: usize,
+    concurrency: usize,
+) -> io::Result<Vec<Run>> {
+    let mut runs = Vec::new();
+
+    for &size in sizes {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8 + 1).collect();
+        let slices: Vec<&[u8]> = data.chunks(chunk_size).collect();
+
+        let mut files = Vec::new();
+        let mut writes = Vec::new();
+
+        for _ in 0..count {
+            let started = Instant::now();
+            let results = backend::parallel(&slices, concurrency, |slice| backend.put_chunk(slice));
+            writes.push(started.elapsed());
+
+            // Whatever went up is removed, even when the rest failed
+            let (ids, errors): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+            let ids: Vec<ChunkId> = ids.into_iter().map(Result::unwrap).collect();
+            files.push(ids);
+
+            if let Some(Err(e)) = errors.into_iter().next() {
+                cleanup(backend, &files);
+                return Err(e);
+            }
+        }
+
+        let mut reads = Vec::new();
+
+        for ids in &files {
+            let started = Instant::now();
+            let results = backend::parallel(ids, concurrency, |id| backend.get_chunk(*id));
+            reads.push(started.elapsed());
+
+            let read = results.into_iter().collect::<io::Result<Vec<_>>>();
+
+            let error = match read {
+                Ok(chunks) if chunks.concat() == data => continue,
+                Ok(_) => io::Error::new(ErrorKind::InvalidData, "a file read back changed"),
+                Err(e) => e,
+            };
+
+            cleanup(backend, &files);
+            return Err(error);
+        }
+
+        cleanup(backend, &files);
+
+        runs.push(Run {
+            size,
+            count,
+            writes: Timings::new(size, writes),
+            reads: Timings::new(size, reads),
+        });
+    }
+
+    Ok(runs)
+}
+
+fn cleanup<B: StorageBackend + ?Sized>(backend: &B, files: &[Vec<ChunkId>]) {
+    for id in files.iter().flatten() {
+        if let Err(e) = backend.delete_chunk(*id) {
+            eprintln!("failed to delete chunk {}: {}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemBackend;
+
+    #[test]
+    fn benches_leave_the_store_as_they_found_it() {
+        let backend = MemBackend::default();
+        let kept = backend.put_chunk(b"kept").unwrap();
+
+        let runs = measure(&backend, &[1000, 2500], 5, 1024, 2).unwrap();
+
+        assert_eq!(runs.len(), 2);
+        assert_eq!((runs[1].size, runs[1].count), (2500, 5));
+
+        for run in &runs {
+            for timings in [&run.writes, &run.reads] {
+                assert_eq!(timings.latencies.len(), 5);
+                assert!(timings.throughput() > 0.0 && timings.throughput().is_finite());
+                assert!(timings.quantile(0.5) <= timings.quantile(0.99));
+                assert_eq!(timings.quantile(0.99), timings.latencies[4]);
+            }
+        }
+
+        assert!(runs[0].to_string().starts_with("1000 byte(s) x 5: write "));
+
+        let stored: Vec<ChunkId> = backend
+            .list_chunks()
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(stored, vec![kept]);
+    }
+}
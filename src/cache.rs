@@ -0,0 +1,344 @@
+use crate::backend::{self, ChunkId, StorageBackend};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// Names the store the cached chunks came from
+const STORE_FILE: &str = "store";
+
+// Which cached chunks were used least recently, and how much room they take
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<ChunkId, (u64, u64)>,
+    order: BTreeMap<u64, ChunkId>,
+    next_use: u64,
+    bytes: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, id: ChunkId, len: u64) {
+        self.remove(id);
+
+        self.entries.insert(id, (len, self.next_use));
+        self.order.insert(self.next_use, id);
+        self.next_use += 1;
+        self.bytes += len;
+    }
+
+    fn remove(&mut self, id: ChunkId) -> bool {
+        let Some((len, used)) = self.entries.remove(&id) else {
+            return false;
+        };
+
+        self.order.remove(&used);
+        self.bytes -= len;
+
+        true
+    }
+
+    fn oldest(&self) -> Option<ChunkId> {
+        self.order.values().next().copied()
+    }
+}
+
+// Keeps downloaded chunks in a directory so they survive a restart. Unlike
+// the mirror it only holds what was read, up to `budget` bytes, dropping the
+// least recently read chunks first.
+pub struct CacheBackend<B> {
+    inner: B,
+    dir: PathBuf,
+    budget: u64,
+    lru: Mutex<Lru>,
+}
+
+impl<B: StorageBackend> CacheBackend<B> {
+    // Picks up what an earlier run left behind, oldest modification first,
+    // since reads bump a chunk's modification time. Chunk ids are only unique
+    // within a store, and not even there once a chunk is deleted, so only
+    // chunks of the same store that it still has are kept.
+    pub fn open(inner: B, dir: PathBuf, budget: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let store = inner.store_id();
+        let same_store = store.is_some() && fs::read_to_string(dir.join(STORE_FILE)).ok() == store;
+        let stored: HashSet<ChunkId> = inner.list_chunks()?.into_iter().map(|(id, _)| id).collect();
+
+        let mut cached = Vec::new();
+        let mut dropped = 0;
+
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+
+            let Some(id) = entry.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                // Left over from a copy that was cut short
+                if entry.path().extension().is_some_and(|ext| ext == "tmp") {
+                    fs::remove_file(entry.path())?;
+                }
+
+                continue;
+            };
+
+            if !same_store || !stored.contains(&id) {
+                fs::remove_file(entry.path())?;
+                dropped += 1;
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            cached.push((metadata.modified()?, id, metadata.len()));
+        }
+
+        match &store {
+            Some(store) => fs::write(dir.join(STORE_FILE), store)?,
+            None => match fs::remove_file(dir.join(STORE_FILE)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            },
+        }
+
+        cached.sort();
+
+        let mut lru = Lru::default();
+
+        for (_, id, len) in cached {
+            lru.touch(id, len);
+        }
+
+        eprintln!(
+            "cache: {} chunk(s), {:.1} MiB on disk, {} stale chunk(s) dropped",
+            lru.entries.len(),
+            lru.bytes as f64 / (1 << 20) as f64,
+            dropped
+        );
+
+        let cache = CacheBackend {
+            inner,
+            dir,
+            budget,
+            lru: Mutex::new(lru),
+        };
+
+        cache.evict(&mut cache.lru.lock().unwrap());
+
+        Ok(cache)
+    }
+
+    fn chunk_path(&self, id: ChunkId) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn evict(&self, lru: &mut Lru) {
+        while lru.bytes > self.budget {
+            let Some(id) = lru.oldest() else {
+                break;
+            };
+
+            lru.remove(id);
+            self.remove_file(id);
+        }
+    }
+
+    fn read_cached(&self, id: ChunkId) -> Option<Vec<u8>> {
+        if !self.lru.lock().unwrap().entries.contains_key(&id) {
+            return None;
+        }
+
+        let data = match fs::read(self.chunk_path(id)) {
+            Ok(data) => data,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    eprintln!("cache: failed to read chunk {}: {}", id, e);
+                }
+
+                self.lru.lock().unwrap().remove(id);
+                return None;
+            }
+        };
+
+        // The modification time is what orders the cache after a restart
+        if let Err(e) = File::options()
+            .write(true)
+            .open(self.chunk_path(id))
+            .and_then(|file| file.set_modified(SystemTime::now()))
+        {
+            eprintln!("cache: failed to touch chunk {}: {}", id, e);
+        }
+
+        self.lru.lock().unwrap().touch(id, data.len() as u64);
+
+        Some(data)
+    }
+
+    // A failed copy only costs a fetch later
+    fn store(&self, id: ChunkId, data: &[u8]) {
+        if data.len() as u64 > self.budget {
+            return;
+        }
+
+        let tmp = self.chunk_path(id).with_extension("tmp");
+
+        if let Err(e) = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, self.chunk_path(id))) {
+            eprintln!("cache: failed to store chunk {}: {}", id, e);
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.touch(id, data.len() as u64);
+        self.evict(&mut lru);
+    }
+
+    fn forget(&self, id: ChunkId) {
+        if self.lru.lock().unwrap().remove(id) {
+            self.remove_file(id);
+        }
+    }
+
+    fn remove_file(&self, id: ChunkId) {
+        match fs::remove_file(self.chunk_path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                eprintln!("cache: failed to remove chunk {}: {}", id, e);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for CacheBackend<B> {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        self.inner.put_chunk(data)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.read_cached(id) {
+            return Ok(data);
+        }
+
+        let data = self.inner.get_chunk(id)?;
+        self.store(id, &data);
+
+        Ok(data)
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        self.inner.delete_chunk(id)?;
+        self.forget(id);
+
+        Ok(())
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.load_index()
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        self.inner.save_index(index)
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        self.inner.save_index_if(expected, index)
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.load_previous_index()
+    }
+
+    fn store_id(&self) -> Option<String> {
+        self.inner.store_id()
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        self.inner.list_chunks()
+    }
+
+    // Only a chunk read whole is kept, part of one isn't worth the room
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.read_cached(id) {
+            return Ok(data[backend::clamp(range, data.len())].to_vec());
+        }
+
+        self.inner.get_chunk_range(id, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Call, TestBackend};
+
+    fn gets(cache: &CacheBackend<TestBackend>, id: ChunkId) -> usize {
+        let calls = cache.inner.calls().into_iter();
+        calls.filter(|call| *call == Call::Get { id }).count()
+    }
+
+    #[test]
+    fn cached_chunks_survive_a_restart() {
+        let dir = testing::temp_dir();
+        let cache = CacheBackend::open(TestBackend::default(), dir.clone(), 1 << 20).unwrap();
+        let id = cache.put_chunk(b"hello").unwrap();
+
+        assert_eq!(cache.get_chunk(id).unwrap(), b"hello");
+        assert_eq!(cache.get_chunk(id).unwrap(), b"hello");
+        assert_eq!(gets(&cache, id), 1);
+
+        let cache = CacheBackend::open(cache.inner, dir.clone(), 1 << 20).unwrap();
+        assert_eq!(cache.get_chunk(id).unwrap(), b"hello");
+        assert_eq!(gets(&cache, id), 1);
+
+        // Deleted chunks never come from the old copy
+        cache.delete_chunk(id).unwrap();
+        assert!(!dir.join(id.to_string()).exists());
+        assert!(cache.get_chunk(id).is_err());
+    }
+
+    #[test]
+    fn the_least_recently_read_chunks_go_first() {
+        let dir = testing::temp_dir();
+        let cache = CacheBackend::open(TestBackend::default(), dir.clone(), 10).unwrap();
+        let ids: Vec<ChunkId> = [b"aaaa", b"bbbb", b"cccc"]
+            .iter()
+            .map(|data| cache.put_chunk(*data).unwrap())
+            .collect();
+
+        cache.get_chunk(ids[0]).unwrap();
+        cache.get_chunk(ids[1]).unwrap();
+        cache.get_chunk(ids[0]).unwrap();
+        cache.get_chunk(ids[2]).unwrap();
+
+        assert!(dir.join(ids[0].to_string()).exists());
+        assert!(!dir.join(ids[1].to_string()).exists());
+        assert!(dir.join(ids[2].to_string()).exists());
+
+        // A smaller budget next time trims what's left, oldest first
+        let cache = CacheBackend::open(cache.inner, dir.clone(), 4).unwrap();
+        assert!(!dir.join(ids[0].to_string()).exists());
+        cache.get_chunk(ids[2]).unwrap();
+        assert_eq!(gets(&cache, ids[2]), 1);
+    }
+
+    #[test]
+    fn chunks_of_another_store_or_deleted_elsewhere_are_never_served() {
+        let dir = testing::temp_dir();
+        let cache = CacheBackend::open(TestBackend::default(), dir.clone(), 1 << 20).unwrap();
+        let kept = cache.put_chunk(b"kept").unwrap();
+        let gone = cache.put_chunk(b"gone").unwrap();
+        cache.get_chunk(kept).unwrap();
+        cache.get_chunk(gone).unwrap();
+
+        // Deleted by gc, which doesn't go through the cache
+        cache.inner.lose_chunk(gone);
+        let cache = CacheBackend::open(cache.inner, dir.clone(), 1 << 20).unwrap();
+        assert!(!dir.join(gone.to_string()).exists());
+        assert_eq!(cache.get_chunk(kept).unwrap(), b"kept");
+        assert_eq!(gets(&cache, kept), 1);
+
+        // Another store hands out the same ids for other data
+        let other = CacheBackend::open(TestBackend::default(), dir.clone(), 1 << 20).unwrap();
+        assert_eq!(other.put_chunk(b"else").unwrap(), kept);
+        assert_eq!(other.get_chunk(kept).unwrap(), b"else");
+        assert_eq!(gets(&other, kept), 1);
+    }
+}
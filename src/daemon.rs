@@ -0,0 +1,80 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Forks into the background and detaches from the terminal. The parent exits
+// straight away, the child carries on as the daemon with its output going to
+// `log`, or nowhere without one. Threads don't survive a fork, so this has to
+// run before any are started.
+pub fn daemonize(log: Option<&Path>) -> io::Result<()> {
+    // Opened first, so a bad path is reported on the terminal
+    let output = match log {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let input = File::open("/dev/null")?;
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        // Exits without running destructors, which would unmount what the child now serves
+        _ => process::exit(0),
+    }
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (file, fd) in [
+        (&input, libc::STDIN_FILENO),
+        (&output, libc::STDOUT_FILENO),
+        (&output, libc::STDERR_FILENO),
+    ] {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+// Holds the process id for as long as it lives, and is removed when dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        fs::write(&path, format!("{}\n", process::id()))?;
+
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn the_pid_file_lasts_until_dropped() {
+        let path = testing::temp_dir().join("discordfs.pid");
+
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        let pid = fs::read_to_string(&path).unwrap();
+        assert_eq!(pid.trim().parse::<u32>().unwrap(), process::id());
+
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
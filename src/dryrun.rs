@@ -0,0 +1,95 @@
+use crate::backend::{ChunkId, MemBackend, StorageBackend};
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+// Keeps everything in memory and logs what a real backend would have been asked to do
+#[derive(Default)]
+pub struct DryRunBackend {
+    inner: MemBackend,
+    chunks: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl StorageBackend for DryRunBackend {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        let id = self.inner.put_chunk(data)?;
+
+        let chunks = self.chunks.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = self.bytes.fetch_add(data.len() as u64, Ordering::Relaxed) + data.len() as u64;
+
+        println!(
+            "dry run: would upload chunk {} ({} bytes), {} chunk(s) and {} bytes so far",
+            id,
+            data.len(),
+            chunks,
+            bytes
+        );
+
+        Ok(id)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        self.inner.get_chunk(id)
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        println!("dry run: would delete chunk {}", id);
+        self.inner.delete_chunk(id)
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.load_index()
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        println!("dry run: would save the index ({} bytes)", index.len());
+        self.inner.save_index(index)
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        println!("dry run: would save the index ({} bytes)", index.len());
+        self.inner.save_index_if(expected, index)
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.load_previous_index()
+    }
+
+    fn store_id(&self) -> Option<String> {
+        self.inner.store_id()
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        self.inner.list_chunks()
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        self.inner.get_chunk_range(id, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Caller, DEFAULT_CHUNK_SIZE, FS};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn a_multi_chunk_file_is_counted_but_kept_in_memory() {
+        let mut fs = FS::new(DryRunBackend::default());
+        let data = vec![7; 2 * DEFAULT_CHUNK_SIZE + 100];
+
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, &data).unwrap();
+        fs.do_release(ino, 0).unwrap();
+
+        assert_eq!(fs.backend.chunks.load(Ordering::Relaxed), 3);
+        assert_eq!(fs.backend.bytes.load(Ordering::Relaxed), data.len() as u64);
+        assert_eq!(fs.do_read(0, ino, 0, data.len() as u32).unwrap(), data);
+    }
+}
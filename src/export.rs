@@ -0,0 +1,345 @@
+use crate::backend::{self, StorageBackend};
+use crate::index::{Index, IndexFormat};
+use fuser::FileAttr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::process;
+
+// Each file is a `file` line followed by one `data` line per chunk, so
+// neither side ever holds more than a chunk in memory
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line {
+    File {
+        name: String,
+        attr: FileAttr,
+        chunk_size: usize,
+    },
+    Data {
+        data: String,
+    },
+}
+
+pub fn export<B: StorageBackend + ?Sized>(backend: &B, index_path: Option<&Path>, out: &Path) {
+    let Some(index) = Index::fetch(backend, index_path).unwrap() else {
+        eprintln!("no index found");
+        process::exit(1);
+    };
+
+    let mut writer = BufWriter::new(File::create(out).unwrap());
+    let mut files = 0;
+
+    for (name, attr) in &index.lookup_table {
+        let Some(chunks) = index.chunk_table.get(&attr.ino) else {
+            continue;
+        };
+
+        let chunk_size = index
+            .chunk_sizes
+            .get(&attr.ino)
+            .copied()
+            .unwrap_or(crate::DEFAULT_CHUNK_SIZE);
+
+        let file = Line::File {
+            name: name.clone(),
+            attr: *attr,
+            chunk_size,
+        };
+
+        write_line(&mut writer, &file).unwrap();
+
+        for id in chunks {
+            let chunk = match backend.get_chunk(*id) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    eprintln!("failed to fetch chunk {} of {}: {}", id, name, e);
+                    process::exit(1);
+                }
+            };
+
+            if index
+                .checksums
+                .get(id)
+                .is_some_and(|&sum| sum != backend::checksum(&chunk))
+            {
+                eprintln!("chunk {} of {} doesn't match its checksum", id, name);
+                process::exit(1);
+            }
+
+            let data = Line::Data {
+                data: encode_base64(&chunk),
+            };
+
+            write_line(&mut writer, &data).unwrap();
+        }
+
+        files += 1;
+    }
+
+    writer.flush().unwrap();
+    println!("{} file(s) exported", files);
+}
+
+fn write_line(writer: &mut impl Write, line: &Line) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, line)?;
+    writer.write_all(b"\n")
+}
+
+// A file being imported, its chunks are uploaded as their lines come in
+struct Pending {
+    name: String,
+    attr: FileAttr,
+    chunk_size: usize,
+    chunks: Vec<u64>,
+    len: u64,
+}
+
+// Adds the exported files to the store's index under fresh inodes. Names
+// that already exist are left alone.
+pub fn import<B: StorageBackend + ?Sized>(backend: &B, index_path: Option<&Path>, input: &Path) {
+    let mut index = Index::fetch(backend, index_path)
+        .unwrap()
+        .unwrap_or_else(empty_index);
+
+    let reader = BufReader::new(File::open(input).unwrap());
+    let mut pending: Option<Pending> = None;
+    let mut skipping = false;
+    let mut imported = 0;
+
+    for (n, line) in reader.lines().enumerate() {
+        let line: Line = match line.and_then(|line| Ok(serde_json::from_str(&line)?)) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("line {} of {}: {}", n + 1, input.display(), e);
+                process::exit(1);
+            }
+        };
+
+        match line {
+            Line::File {
+                name,
+                attr,
+                chunk_size,
+            } => {
+                if let Some(file) = pending.take() {
+                    finish(&mut index, file);
+                    imported += 1;
+                }
+
+                skipping = index.lookup_table.contains_key(&name);
+
+                if skipping {
+                    eprintln!("skipping {}, a file with that name already exists", name);
+                    continue;
+                }
+
+                pending = Some(Pending {
+                    name,
+                    attr,
+                    chunk_size,
+                    chunks: Vec::new(),
+                    len: 0,
+                });
+            }
+            Line::Data { .. } if skipping => {}
+            Line::Data { data } => {
+                let Some(file) = pending.as_mut() else {
+                    eprintln!("line {} has data before any file", n + 1);
+                    process::exit(1);
+                };
+
+                let chunk = decode_base64(&data).unwrap_or_else(|e| {
+                    eprintln!("line {} of {}: {}", n + 1, input.display(), e);
+                    process::exit(1);
+                });
+
+                let id = backend.put_chunk(&chunk).unwrap();
+                index.checksums.insert(id, backend::checksum(&chunk));
+                file.chunks.push(id);
+                file.len += chunk.len() as u64;
+            }
+        }
+    }
+
+    if let Some(file) = pending.take() {
+        finish(&mut index, file);
+        imported += 1;
+    }
+
+    index.write(backend, index_path).unwrap();
+
+    println!("{} file(s) imported", imported);
+}
+
+fn finish(index: &mut Index, file: Pending) {
+    // A cut off export would otherwise come back as a silently shorter file
+    if file.len != file.attr.size {
+        eprintln!(
+            "{} should hold {} bytes but its data has {}",
+            file.name, file.attr.size, file.len
+        );
+        process::exit(1);
+    }
+
+    let ino = index.free_inodes.pop().unwrap_or_else(|| {
+        index.last_inode += 1;
+        index.last_inode
+    });
+
+    index.chunk_table.insert(ino, file.chunks);
+    index.chunk_sizes.insert(ino, file.chunk_size);
+    index.path_table.insert(ino, file.name.clone());
+    index
+        .lookup_table
+        .insert(file.name, FileAttr { ino, ..file.attr });
+}
+
+fn empty_index() -> Index {
+    Index {
+        lookup_table: HashMap::from([(
+            ".".to_string(),
+            crate::root_attr(crate::DEFAULT_ROOT_MODE),
+        )]),
+        chunk_table: HashMap::new(),
+        chunk_sizes: HashMap::new(),
+        path_table: HashMap::new(),
+        last_inode: crate::ROOT_INO,
+        free_inodes: Vec::new(),
+        trash: HashMap::new(),
+        uploads: HashMap::new(),
+        checksums: HashMap::new(),
+        version: 0,
+        format: IndexFormat::Json,
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | (byte as u32) << (16 - 8 * i)
+        });
+
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn decode_base64(encoded: &str) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid base64");
+
+    if !encoded.len().is_multiple_of(4) {
+        return Err(invalid());
+    }
+
+    let mut data = Vec::with_capacity(encoded.len() / 4 * 3);
+
+    for group in encoded.as_bytes().chunks(4) {
+        let padding = group.iter().rev().take_while(|&&c| c == b'=').count();
+
+        if padding > 2 {
+            return Err(invalid());
+        }
+
+        let mut bits = 0u32;
+
+        for (i, &c) in group[..4 - padding].iter().enumerate() {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return Err(invalid()),
+            };
+
+            bits |= (value as u32) << (18 - 6 * i);
+        }
+
+        data.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemBackend;
+    use crate::{testing, Caller, FS};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn base64_matches_the_standard_alphabet() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foobar", "Zm9vYmFy"),
+            (b"\xff\xfe\x00", "//4A"),
+        ] {
+            assert_eq!(encode_base64(data), encoded);
+            assert_eq!(decode_base64(encoded).unwrap(), data);
+        }
+
+        assert!(decode_base64("Zm9").is_err());
+        assert!(decode_base64("Zm9*").is_err());
+        assert!(decode_base64("Z===").is_err());
+    }
+
+    #[test]
+    fn imports_bring_back_what_was_exported() {
+        let mut fs = FS::new_for_test();
+        fs.chunk_size = crate::MIN_CHUNK_SIZE;
+        let data: Vec<u8> = (0..crate::MIN_CHUNK_SIZE + 10)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, &data).unwrap();
+        fs.do_release(ino, 0).unwrap();
+        fs.do_create(Caller::mounter(), OsStr::new("empty.txt"), 0o644, 0)
+            .unwrap();
+
+        let file = testing::temp_dir().join("export.jsonl");
+        export(fs.backend(), None, &file);
+
+        // Run twice, the second time every name is already taken
+        let store = MemBackend::default();
+        import(&store, None, &file);
+        import(&store, None, &file);
+
+        let index = Index::fetch(&store, None).unwrap().unwrap();
+        assert_eq!(index.lookup_table.len(), 3);
+
+        let attr = index.lookup_table["big.bin"];
+        assert_eq!(attr.size, data.len() as u64);
+        assert_eq!(index.path_table[&attr.ino], "big.bin");
+        assert_eq!(index.chunk_sizes[&attr.ino], crate::MIN_CHUNK_SIZE);
+
+        let mut read = Vec::new();
+
+        for id in &index.chunk_table[&attr.ino] {
+            read.extend(store.get_chunk(*id).unwrap());
+        }
+
+        assert_eq!(read, data);
+    }
+}
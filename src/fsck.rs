@@ -0,0 +1,430 @@
+use crate::backend::{self, ChunkId, StorageBackend};
+use crate::index::Index;
+use fuser::FileType;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::process;
+
+pub enum Problem {
+    MissingManifest {
+        name: String,
+        ino: u64,
+    },
+    MissingChunk {
+        name: String,
+        id: ChunkId,
+    },
+    UnreadableChunk {
+        name: String,
+        id: ChunkId,
+        error: io::Error,
+    },
+    CorruptChunk {
+        name: String,
+        id: ChunkId,
+    },
+    MisalignedChunk {
+        name: String,
+        id: ChunkId,
+        len: u64,
+        expected: u64,
+    },
+    SizeMismatch {
+        name: String,
+        size: u64,
+        len: u64,
+    },
+    OrphanChunks {
+        ino: u64,
+        count: usize,
+    },
+    DanglingPath {
+        ino: u64,
+        name: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MissingManifest { name, ino } => {
+                write!(f, "{} (inode {}) has no chunk manifest", name, ino)
+            }
+            Problem::MissingChunk { name, id } => {
+                write!(f, "{} references chunk {} which no longer exists", name, id)
+            }
+            Problem::UnreadableChunk { name, id, error } => {
+                write!(
+                    f,
+                    "{} references chunk {} which can't be fetched: {}",
+                    name, id, error
+                )
+            }
+            Problem::CorruptChunk { name, id } => {
+                write!(
+                    f,
+                    "{} references chunk {} whose checksum doesn't match",
+                    name, id
+                )
+            }
+            Problem::MisalignedChunk {
+                name,
+                id,
+                len,
+                expected,
+            } => {
+                write!(
+                    f,
+                    "{} has chunk {} of {} bytes where {} bytes were expected",
+                    name, id, len, expected
+                )
+            }
+            Problem::SizeMismatch { name, size, len } => {
+                write!(
+                    f,
+                    "{} reports {} bytes but its chunks hold {} bytes",
+                    name, size, len
+                )
+            }
+            Problem::OrphanChunks { ino, count } => {
+                write!(f, "inode {} has {} chunk(s) but no entry", ino, count)
+            }
+            Problem::DanglingPath { ino, name } => {
+                write!(
+                    f,
+                    "inode {} points at {} which does not refer back to it",
+                    ino, name
+                )
+            }
+        }
+    }
+}
+
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub fixed: usize,
+}
+
+// With `verify`, chunk contents are also compared against their recorded checksums
+pub fn check<B: StorageBackend + ?Sized>(
+    backend: &B,
+    index: &mut Index,
+    prune: bool,
+    verify: bool,
+) -> Report {
+    let mut problems = Vec::new();
+
+    for (name, attr) in &index.lookup_table {
+        if attr.kind == FileType::Directory {
+            continue;
+        }
+
+        let Some(chunks) = index.chunk_table.get(&attr.ino) else {
+            problems.push(Problem::MissingManifest {
+                name: name.clone(),
+                ino: attr.ino,
+            });
+            continue;
+        };
+
+        let chunk_size = index
+            .chunk_sizes
+            .get(&attr.ino)
+            .copied()
+            .unwrap_or(crate::DEFAULT_CHUNK_SIZE) as u64;
+
+        let mut len = 0;
+        let mut complete = true;
+
+        for (i, id) in chunks.iter().enumerate() {
+            match backend.get_chunk(*id) {
+                Ok(chunk) => {
+                    len += chunk.len() as u64;
+
+                    // Only the last chunk may come up short
+                    if i + 1 < chunks.len() && chunk.len() as u64 != chunk_size {
+                        problems.push(Problem::MisalignedChunk {
+                            name: name.clone(),
+                            id: *id,
+                            len: chunk.len() as u64,
+                            expected: chunk_size,
+                        });
+                    }
+
+                    let expected = index.checksums.get(id);
+
+                    if verify && expected.is_some_and(|sum| *sum != backend::checksum(&chunk)) {
+                        problems.push(Problem::CorruptChunk {
+                            name: name.clone(),
+                            id: *id,
+                        });
+                    }
+                }
+                // Deleted from the store, as opposed to the store being unreachable
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    complete = false;
+                    problems.push(Problem::MissingChunk {
+                        name: name.clone(),
+                        id: *id,
+                    });
+                }
+                Err(error) => {
+                    complete = false;
+                    problems.push(Problem::UnreadableChunk {
+                        name: name.clone(),
+                        id: *id,
+                        error,
+                    });
+                }
+            }
+        }
+
+        if complete && len != attr.size {
+            problems.push(Problem::SizeMismatch {
+                name: name.clone(),
+                size: attr.size,
+                len,
+            });
+        }
+    }
+
+    let mut orphans = Vec::new();
+
+    for (ino, chunks) in &index.chunk_table {
+        let referenced = index.lookup_table.values().any(|attr| attr.ino == *ino)
+            || index.trash.contains_key(ino);
+
+        if !referenced {
+            orphans.push(*ino);
+            problems.push(Problem::OrphanChunks {
+                ino: *ino,
+                count: chunks.len(),
+            });
+        }
+    }
+
+    let mut dangling = Vec::new();
+
+    for (ino, name) in &index.path_table {
+        if index.lookup_table.get(name).map(|attr| attr.ino) != Some(*ino) {
+            dangling.push(*ino);
+            problems.push(Problem::DanglingPath {
+                ino: *ino,
+                name: name.clone(),
+            });
+        }
+    }
+
+    let mut fixed = 0;
+
+    if prune {
+        for ino in orphans {
+            index.chunk_sizes.remove(&ino);
+
+            for id in index.chunk_table.remove(&ino).unwrap_or_default() {
+                index.checksums.remove(&id);
+
+                // Already gone is as good as deleted
+                let _ = backend.delete_chunk(id);
+            }
+
+            fixed += 1;
+        }
+
+        for ino in dangling {
+            index.path_table.remove(&ino);
+            fixed += 1;
+        }
+    }
+
+    Report { problems, fixed }
+}
+
+pub fn run<B: StorageBackend + ?Sized>(
+    backend: &B,
+    index_path: Option<&Path>,
+    prune: bool,
+    verify: bool,
+) {
+    let Some(mut index) = Index::fetch(backend, index_path).unwrap() else {
+        eprintln!("no index found");
+        process::exit(1);
+    };
+
+    let report = check(backend, &mut index, prune, verify);
+
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+
+    println!(
+        "{} problem(s) found, {} fixed",
+        report.problems.len(),
+        report.fixed
+    );
+
+    if report.fixed > 0 {
+        index.write(backend, index_path).unwrap();
+    }
+
+    if report.fixed < report.problems.len() {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemBackend;
+    use crate::index::IndexFormat;
+    use fuser::FileAttr;
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+
+    fn file(ino: u64, size: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            kind: FileType::RegularFile,
+            ..crate::root_attr(0o644)
+        }
+    }
+
+    #[test]
+    fn a_dangling_reference_is_the_only_problem() {
+        let backend = MemBackend::default();
+        let kept = backend.put_chunk(b"hello").unwrap();
+
+        let mut index = Index {
+            lookup_table: HashMap::from([
+                ("kept.txt".to_string(), file(2, 5)),
+                ("lost.txt".to_string(), file(3, 5)),
+            ]),
+            chunk_table: HashMap::from([(2, vec![kept]), (3, vec![kept + 1])]),
+            chunk_sizes: HashMap::new(),
+            path_table: HashMap::from([(2, "kept.txt".to_string()), (3, "lost.txt".to_string())]),
+            last_inode: 3,
+            free_inodes: Vec::new(),
+            trash: HashMap::new(),
+            uploads: HashMap::new(),
+            checksums: HashMap::new(),
+            version: 0,
+            format: IndexFormat::Json,
+        };
+
+        let report = check(&backend, &mut index, true, false);
+
+        assert_eq!(report.problems.len(), 1);
+        assert!(matches!(
+            &report.problems[0],
+            Problem::MissingChunk { name, id } if name == "lost.txt" && *id == kept + 1
+        ));
+        assert_eq!(report.fixed, 0);
+    }
+
+    #[test]
+    fn chunks_that_cant_be_fetched_arent_taken_for_deleted_ones() {
+        let mut fs = crate::FS::new_for_test();
+        let ino = fs
+            .do_create(crate::Caller::mounter(), OsStr::new("file.txt"), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, b"hello").unwrap();
+        fs.do_release(ino, 0).unwrap();
+        let id = fs.chunk_table[&ino][0];
+        let mut index = Index::fetch(fs.backend(), None).unwrap().unwrap();
+
+        fs.backend().fail_next(ErrorKind::TimedOut);
+        let report = check(fs.backend(), &mut index, false, false);
+        assert_eq!(report.problems.len(), 1);
+        assert!(matches!(
+            &report.problems[0],
+            Problem::UnreadableChunk { id: bad, error, .. }
+                if *bad == id && error.kind() == ErrorKind::TimedOut
+        ));
+    }
+
+    #[test]
+    fn prune_removes_orphans_and_dangling_paths() {
+        let backend = MemBackend::default();
+        let orphan = backend.put_chunk(b"hello").unwrap();
+
+        let mut index = Index {
+            lookup_table: HashMap::new(),
+            chunk_table: HashMap::from([(2, vec![orphan])]),
+            chunk_sizes: HashMap::new(),
+            path_table: HashMap::from([(2, "gone.txt".to_string())]),
+            last_inode: 2,
+            free_inodes: Vec::new(),
+            trash: HashMap::new(),
+            uploads: HashMap::new(),
+            checksums: HashMap::new(),
+            version: 0,
+            format: IndexFormat::Json,
+        };
+
+        let report = check(&backend, &mut index, true, false);
+
+        assert_eq!(report.problems.len(), 2);
+        assert_eq!(report.fixed, 2);
+        assert!(index.chunk_table.is_empty());
+        assert!(index.path_table.is_empty());
+        assert!(backend.get_chunk(orphan).is_err());
+    }
+
+    #[test]
+    fn verify_catches_chunks_changed_behind_our_back() {
+        let mut fs = crate::FS::new_for_test();
+        let ino = fs
+            .do_create(crate::Caller::mounter(), OsStr::new("file.txt"), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, b"hello").unwrap();
+        fs.do_release(ino, 0).unwrap();
+        let id = fs.chunk_table[&ino][0];
+
+        // Same length, so only the checksum can tell
+        fs.backend.corrupt_chunk(id, b"jello");
+        let mut index = Index::fetch(&fs.backend, None).unwrap().unwrap();
+        assert!(check(&fs.backend, &mut index, false, false)
+            .problems
+            .is_empty());
+
+        let report = check(&fs.backend, &mut index, false, true);
+        assert_eq!(report.problems.len(), 1);
+        assert!(matches!(
+            &report.problems[0],
+            Problem::CorruptChunk { name, id: bad } if name == "file.txt" && *bad == id
+        ));
+    }
+
+    #[test]
+    fn files_are_checked_against_the_chunk_size_they_were_split_at() {
+        let mut fs = crate::FS::new_for_test();
+        fs.chunk_size = crate::MIN_CHUNK_SIZE;
+        let ino = fs
+            .do_create(crate::Caller::mounter(), OsStr::new("file.bin"), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, &vec![1; crate::MIN_CHUNK_SIZE + 1])
+            .unwrap();
+        fs.do_release(ino, 0).unwrap();
+
+        let mut index = Index::fetch(&fs.backend, None).unwrap().unwrap();
+        assert_eq!(index.chunk_sizes[&ino], crate::MIN_CHUNK_SIZE);
+        assert!(check(&fs.backend, &mut index, false, false)
+            .problems
+            .is_empty());
+
+        // Without the record the default is assumed, which doesn't fit
+        index.chunk_sizes.clear();
+        let report = check(&fs.backend, &mut index, false, false);
+        assert_eq!(report.problems.len(), 1);
+        assert!(matches!(
+            &report.problems[0],
+            Problem::MisalignedChunk { len, expected, .. }
+                if *len == crate::MIN_CHUNK_SIZE as u64 && *expected == crate::DEFAULT_CHUNK_SIZE as u64
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+use crate::backend::{ChunkId, StorageBackend};
+use crate::index::Index;
+use std::collections::HashSet;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, SystemTime};
+
+// Deletes stored chunks the index doesn't reference. Chunks younger than
+// `grace` are left alone, they may belong to an upload whose index isn't saved yet.
+pub fn run<B: StorageBackend + ?Sized>(backend: &B, index_path: Option<&Path>, grace: Duration) {
+    let Some(index) = Index::fetch(backend, index_path).unwrap() else {
+        eprintln!("no index found");
+        process::exit(1);
+    };
+
+    // The previous generation is what a damaged index falls back on, so its
+    // chunks are kept too. Without knowing them nothing is safe to delete.
+    let previous = match Index::fetch_previous(backend) {
+        Ok(previous) => previous,
+        Err(e) => {
+            eprintln!("failed to read the previous index, deleting nothing: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut kept = referenced(&index);
+    kept.extend(previous.as_ref().map(referenced).unwrap_or_default());
+
+    let now = SystemTime::now();
+    let mut deleted = 0;
+    let mut failed = 0;
+
+    for (id, written) in backend.list_chunks().unwrap() {
+        if kept.contains(&id) {
+            continue;
+        }
+
+        if now.duration_since(written).unwrap_or_default() < grace {
+            continue;
+        }
+
+        match backend.delete_chunk(id) {
+            Ok(()) => deleted += 1,
+            Err(e) => {
+                eprintln!("failed to delete chunk {}: {}", id, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} orphaned chunk(s) deleted", deleted);
+
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+// Every chunk a file, a file in the trash or an unfinished upload uses
+pub fn referenced(index: &Index) -> HashSet<ChunkId> {
+    index
+        .chunk_table
+        .values()
+        .flatten()
+        .copied()
+        .chain(index.uploads.values().flat_map(|upload| upload.uploaded()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::DirBackend;
+    use crate::{testing, Caller, FS};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn only_old_unreferenced_chunks_are_deleted() {
+        let mut fs = FS::new_for_test();
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new("kept.txt"), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, b"kept").unwrap();
+        fs.do_release(ino, 0).unwrap();
+        let kept = fs.chunk_table[&ino][0];
+        let orphan = fs.backend.put_chunk(b"orphan").unwrap();
+
+        // Too young to tell from an upload in flight
+        run(&fs.backend, None, Duration::from_secs(60));
+        assert!(fs.backend.get_chunk(orphan).is_ok());
+
+        run(&fs.backend, None, Duration::ZERO);
+        assert!(fs.backend.get_chunk(orphan).is_err());
+        assert_eq!(fs.backend.get_chunk(kept).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn chunks_only_the_previous_generation_uses_are_kept() {
+        let mut fs = FS::new(DirBackend::open(testing::temp_dir()).unwrap());
+        fs.save_index();
+        let old = fs.backend.put_chunk(b"old").unwrap();
+        let orphan = fs.backend.put_chunk(b"orphan").unwrap();
+
+        let mut index = Index::fetch(&fs.backend, None).unwrap().unwrap();
+        index.chunk_table.insert(7, vec![old]);
+        index.write(&fs.backend, None).unwrap();
+        index.chunk_table.remove(&7);
+        index.write(&fs.backend, None).unwrap();
+
+        run(&fs.backend, None, Duration::ZERO);
+        assert_eq!(fs.backend.get_chunk(old).unwrap(), b"old");
+        assert!(fs.backend.get_chunk(orphan).is_err());
+    }
+}
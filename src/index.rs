@@ -0,0 +1,769 @@
+use crate::backend::{self, ChunkId, StorageBackend};
+use fuser::{FileAttr, FileType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Marks a byte that isn't valid UTF-8 in an encoded name, or stands for itself when doubled
+const NAME_ESCAPE: char = '\u{10FFFF}';
+
+// Escaped bytes 0x80..=0xFF become the characters from here on, which never reach NAME_ESCAPE
+const NAME_BYTE_BASE: u32 = 0x10FE00;
+
+// Starts a binary index, which JSON never does. The layout version follows it.
+const BINARY_MAGIC: &[u8; 8] = b"DFSINDEX";
+const BINARY_VERSION: u32 = 1;
+
+// How the index is stored. Either is read whatever was asked for, the
+// format only decides how it's written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IndexFormat {
+    #[default]
+    Json,
+    // Length-prefixed fields, under half the size of JSON for large trees
+    Binary,
+}
+
+impl IndexFormat {
+    pub fn parse(format: &str) -> Self {
+        match format {
+            "binary" => IndexFormat::Binary,
+            _ => IndexFormat::Json,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Index {
+    pub lookup_table: HashMap<String, FileAttr>,
+    pub chunk_table: HashMap<u64, Vec<ChunkId>>,
+    // The size files were split at, which is the default for older indexes
+    #[serde(default)]
+    pub chunk_sizes: HashMap<u64, usize>,
+    pub path_table: HashMap<u64, String>,
+    pub last_inode: u64,
+    #[serde(default)]
+    pub free_inodes: Vec<u64>,
+    // Unlinked files whose chunks are kept until the trash is emptied
+    #[serde(default)]
+    pub trash: HashMap<u64, (String, FileAttr)>,
+    #[serde(default)]
+    pub uploads: HashMap<u64, PartialUpload>,
+    #[serde(default)]
+    pub checksums: HashMap<ChunkId, u64>,
+    // Bumped by every save, so a mount can tell another one saved since it last looked
+    #[serde(default)]
+    pub version: u64,
+    // What it was read from, and so what it's written back as
+    #[serde(skip)]
+    pub format: IndexFormat,
+}
+
+// Chunks already uploaded by a flush that failed partway, for the data with this hash
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PartialUpload {
+    pub hash: u64,
+    pub chunks: Vec<Option<ChunkId>>,
+}
+
+impl PartialUpload {
+    pub fn uploaded(&self) -> Vec<ChunkId> {
+        self.chunks.iter().flatten().copied().collect()
+    }
+}
+
+impl Index {
+    // The backend's copy or a local index file, when given and present,
+    // whichever is the newer version. Other mounts only save to the backend,
+    // so the local file is a fallback for when that has none or fails.
+    pub fn fetch<B: StorageBackend + ?Sized>(
+        backend: &B,
+        path: Option<&Path>,
+    ) -> io::Result<Option<Self>> {
+        let local = match path.filter(|path| path.exists()) {
+            Some(path) => Some(Index::load(path)?),
+            None => None,
+        };
+
+        let shared = match Index::fetch_shared(backend) {
+            Ok(shared) => shared,
+            Err(e) if local.is_some() => {
+                eprintln!("failed to load the index: {}, using the local one", e);
+                None
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(match (local, shared) {
+            (Some(local), Some(shared)) if local.version > shared.version => Some(local),
+            (local, None) => local,
+            (_, shared) => shared,
+        })
+    }
+
+    fn fetch_shared<B: StorageBackend + ?Sized>(backend: &B) -> io::Result<Option<Self>> {
+        let error = match parse(backend.load_index()) {
+            Ok(Some(index)) => return Ok(Some(index)),
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("failed to load the index: {}, trying the previous one", e);
+                Some(e)
+            }
+        };
+
+        match parse(backend.load_previous_index())? {
+            Some(index) => Ok(Some(index)),
+            None => error.map_or(Ok(None), Err),
+        }
+    }
+
+    // The generation `fetch` falls back on when the current one is unreadable
+    pub fn fetch_previous<B: StorageBackend + ?Sized>(backend: &B) -> io::Result<Option<Self>> {
+        parse(backend.load_previous_index())
+    }
+
+    // Saves where `fetch` would find it, as a new version
+    pub fn write<B: StorageBackend + ?Sized>(
+        &mut self,
+        backend: &B,
+        path: Option<&Path>,
+    ) -> io::Result<()> {
+        self.version += 1;
+
+        match path {
+            Some(path) => self.save(path),
+            None => backend.save_index(&self.to_bytes()?),
+        }
+    }
+
+    // Moves the file at inode `from` to `to`, along with everything kept by inode
+    pub fn renumber(&mut self, from: u64, to: u64) {
+        for attr in self
+            .lookup_table
+            .values_mut()
+            .filter(|attr| attr.ino == from)
+        {
+            attr.ino = to;
+        }
+
+        if let Some(chunks) = self.chunk_table.remove(&from) {
+            self.chunk_table.insert(to, chunks);
+        }
+
+        if let Some(size) = self.chunk_sizes.remove(&from) {
+            self.chunk_sizes.insert(to, size);
+        }
+
+        if let Some(path) = self.path_table.remove(&from) {
+            self.path_table.insert(to, path);
+        }
+
+        if let Some(upload) = self.uploads.remove(&from) {
+            self.uploads.insert(to, upload);
+        }
+
+        self.last_inode = self.last_inode.max(to);
+    }
+
+    // One fingerprint per entry, see `fingerprint`
+    pub fn fingerprints(&self) -> HashMap<String, u64> {
+        self.lookup_table
+            .iter()
+            .map(|(name, attr)| {
+                let chunks = self.chunk_table.get(&attr.ino);
+                (name.clone(), fingerprint(attr, chunks, &self.checksums))
+            })
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        match bytes.strip_prefix(BINARY_MAGIC) {
+            Some(bytes) => decode_binary(bytes),
+            None => Ok(serde_json::from_slice(bytes)?),
+        }
+    }
+
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        match self.format {
+            IndexFormat::Json => Ok(serde_json::to_vec(self)?),
+            IndexFormat::Binary => Ok(encode_binary(self)),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Index::from_bytes(&fs::read(path)?)
+    }
+
+    // Written to a temporary file first so a crash never leaves a torn index
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        writer.write_all(&self.to_bytes()?)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        fs::rename(tmp, path)
+    }
+}
+
+// Every number is little-endian, and every string and table is preceded by
+// its length. The tables come in the order of Index's fields.
+fn encode_binary(index: &Index) -> Vec<u8> {
+    let mut out = Encoder(BINARY_MAGIC.to_vec());
+    out.u32(BINARY_VERSION);
+    out.u64(index.version);
+    out.u64(index.last_inode);
+
+    out.len(index.lookup_table.len());
+    for (name, attr) in &index.lookup_table {
+        out.str(name);
+        out.attr(attr);
+    }
+
+    out.len(index.chunk_table.len());
+    for (ino, chunks) in &index.chunk_table {
+        out.u64(*ino);
+        out.len(chunks.len());
+        chunks.iter().for_each(|id| out.u64(*id));
+    }
+
+    out.len(index.chunk_sizes.len());
+    for (ino, size) in &index.chunk_sizes {
+        out.u64(*ino);
+        out.len(*size);
+    }
+
+    out.len(index.path_table.len());
+    for (ino, path) in &index.path_table {
+        out.u64(*ino);
+        out.str(path);
+    }
+
+    out.len(index.free_inodes.len());
+    index.free_inodes.iter().for_each(|ino| out.u64(*ino));
+
+    out.len(index.trash.len());
+    for (ino, (name, attr)) in &index.trash {
+        out.u64(*ino);
+        out.str(name);
+        out.attr(attr);
+    }
+
+    out.len(index.uploads.len());
+    for (ino, upload) in &index.uploads {
+        out.u64(*ino);
+        out.u64(upload.hash);
+        out.len(upload.chunks.len());
+
+        for id in &upload.chunks {
+            out.u8(id.is_some() as u8);
+            out.u64(id.unwrap_or(0));
+        }
+    }
+
+    out.len(index.checksums.len());
+    for (id, sum) in &index.checksums {
+        out.u64(*id);
+        out.u64(*sum);
+    }
+
+    out.0
+}
+
+fn decode_binary(bytes: &[u8]) -> io::Result<Index> {
+    let mut input = Decoder(bytes);
+
+    let version = input.u32()?;
+    if version != BINARY_VERSION {
+        return Err(invalid(format!(
+            "binary index version {} isn't supported",
+            version
+        )));
+    }
+
+    let mut index = Index {
+        lookup_table: HashMap::new(),
+        chunk_table: HashMap::new(),
+        chunk_sizes: HashMap::new(),
+        path_table: HashMap::new(),
+        last_inode: 0,
+        free_inodes: Vec::new(),
+        trash: HashMap::new(),
+        uploads: HashMap::new(),
+        checksums: HashMap::new(),
+        version: input.u64()?,
+        format: IndexFormat::Binary,
+    };
+    index.last_inode = input.u64()?;
+
+    for _ in 0..input.len()? {
+        index.lookup_table.insert(input.str()?, input.attr()?);
+    }
+
+    for _ in 0..input.len()? {
+        let ino = input.u64()?;
+        let chunks = (0..input.len()?)
+            .map(|_| input.u64())
+            .collect::<io::Result<_>>()?;
+        index.chunk_table.insert(ino, chunks);
+    }
+
+    for _ in 0..input.len()? {
+        index.chunk_sizes.insert(input.u64()?, input.len()?);
+    }
+
+    for _ in 0..input.len()? {
+        index.path_table.insert(input.u64()?, input.str()?);
+    }
+
+    for _ in 0..input.len()? {
+        index.free_inodes.push(input.u64()?);
+    }
+
+    for _ in 0..input.len()? {
+        index
+            .trash
+            .insert(input.u64()?, (input.str()?, input.attr()?));
+    }
+
+    for _ in 0..input.len()? {
+        let ino = input.u64()?;
+        let hash = input.u64()?;
+        let chunks = (0..input.len()?)
+            .map(|_| {
+                let present = input.u8()? != 0;
+                let id = input.u64()?;
+                Ok(present.then_some(id))
+            })
+            .collect::<io::Result<_>>()?;
+        index.uploads.insert(ino, PartialUpload { hash, chunks });
+    }
+
+    for _ in 0..input.len()? {
+        index.checksums.insert(input.u64()?, input.u64()?);
+    }
+
+    if !input.0.is_empty() {
+        return Err(invalid("trailing bytes after the binary index"));
+    }
+
+    Ok(index)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+const FILE_TYPES: [FileType; 7] = [
+    FileType::NamedPipe,
+    FileType::CharDevice,
+    FileType::BlockDevice,
+    FileType::Directory,
+    FileType::RegularFile,
+    FileType::Symlink,
+    FileType::Socket,
+];
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u64(len as u64);
+    }
+
+    fn str(&mut self, value: &str) {
+        self.len(value.len());
+        self.0.extend_from_slice(value.as_bytes());
+    }
+
+    // Seconds from the epoch, negative before it, and the nanoseconds on top
+    fn time(&mut self, time: SystemTime) {
+        let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+            Err(e) => {
+                let before = e.duration();
+                match before.subsec_nanos() {
+                    0 => (-(before.as_secs() as i64), 0),
+                    nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+                }
+            }
+        };
+
+        self.u64(secs as u64);
+        self.u32(nanos);
+    }
+
+    fn attr(&mut self, attr: &FileAttr) {
+        self.u64(attr.ino);
+        self.u64(attr.size);
+        self.u64(attr.blocks);
+        self.time(attr.atime);
+        self.time(attr.mtime);
+        self.time(attr.ctime);
+        self.time(attr.crtime);
+        self.u8(FILE_TYPES
+            .iter()
+            .position(|&kind| kind == attr.kind)
+            .unwrap() as u8);
+        self.u16(attr.perm);
+        self.u32(attr.nlink);
+        self.u32(attr.uid);
+        self.u32(attr.gid);
+        self.u32(attr.rdev);
+        self.u32(attr.blksize);
+        self.u32(attr.flags);
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let Some((bytes, rest)) = self.0.split_first_chunk() else {
+            return Err(invalid("the binary index is cut short"));
+        };
+
+        self.0 = rest;
+        Ok(*bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?).map_err(|_| invalid("a length in the binary index overflows"))
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let len = self.len()?;
+
+        if len > self.0.len() {
+            return Err(invalid("the binary index is cut short"));
+        }
+
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        String::from_utf8(bytes.to_vec()).map_err(|e| invalid(e.to_string()))
+    }
+
+    fn time(&mut self) -> io::Result<SystemTime> {
+        let secs = self.u64()? as i64;
+        let nanos = Duration::from_nanos(self.u32()? as u64);
+
+        let time = match secs {
+            0.. => UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64)),
+            _ => UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs())),
+        };
+
+        time.and_then(|time| time.checked_add(nanos))
+            .ok_or_else(|| invalid("a time in the binary index is out of range"))
+    }
+
+    fn attr(&mut self) -> io::Result<FileAttr> {
+        Ok(FileAttr {
+            ino: self.u64()?,
+            size: self.u64()?,
+            blocks: self.u64()?,
+            atime: self.time()?,
+            mtime: self.time()?,
+            ctime: self.time()?,
+            crtime: self.time()?,
+            kind: *FILE_TYPES
+                .get(self.u8()? as usize)
+                .ok_or_else(|| invalid("unknown file type in the binary index"))?,
+            perm: self.u16()?,
+            nlink: self.u32()?,
+            uid: self.u32()?,
+            gid: self.u32()?,
+            rdev: self.u32()?,
+            blksize: self.u32()?,
+            flags: self.u32()?,
+        })
+    }
+}
+
+// Changes whenever the entry points at another inode, is resized or has its
+// chunks rewritten
+pub fn fingerprint(
+    attr: &FileAttr,
+    chunks: Option<&Vec<ChunkId>>,
+    checksums: &HashMap<ChunkId, u64>,
+) -> u64 {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&attr.ino.to_le_bytes());
+    bytes.extend_from_slice(&attr.size.to_le_bytes());
+
+    for id in chunks.into_iter().flatten() {
+        bytes.extend_from_slice(&id.to_le_bytes());
+        bytes.extend_from_slice(&checksums.get(id).copied().unwrap_or(0).to_le_bytes());
+    }
+
+    backend::checksum(&bytes)
+}
+
+// File names are stored as strings. UTF-8 names are kept as they are, so
+// older indexes stay valid, and any other bytes are escaped losslessly.
+pub fn encode_name(name: &OsStr) -> String {
+    let mut encoded = String::new();
+
+    for chunk in name.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == NAME_ESCAPE {
+                encoded.push(NAME_ESCAPE);
+            }
+
+            encoded.push(c);
+        }
+
+        for &byte in chunk.invalid() {
+            encoded.push(NAME_ESCAPE);
+            encoded.extend(char::from_u32(NAME_BYTE_BASE + byte as u32));
+        }
+    }
+
+    encoded
+}
+
+pub fn decode_name(name: &str) -> OsString {
+    if !name.contains(NAME_ESCAPE) {
+        return OsString::from(name);
+    }
+
+    let mut bytes = Vec::new();
+    let mut chars = name.chars();
+
+    while let Some(c) = chars.next() {
+        let c = match c {
+            NAME_ESCAPE => match chars.next() {
+                Some(NAME_ESCAPE) | None => NAME_ESCAPE,
+                Some(escaped) => {
+                    match u8::try_from((escaped as u32).wrapping_sub(NAME_BYTE_BASE)) {
+                        Ok(byte) => {
+                            bytes.push(byte);
+                            continue;
+                        }
+                        Err(_) => escaped,
+                    }
+                }
+            },
+            c => c,
+        };
+
+        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+
+    OsString::from_vec(bytes)
+}
+
+fn parse(bytes: io::Result<Option<Vec<u8>>>) -> io::Result<Option<Index>> {
+    bytes?.map(|bytes| Index::from_bytes(&bytes)).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(files: u64) -> Index {
+        let mut index = Index {
+            lookup_table: HashMap::new(),
+            chunk_table: HashMap::new(),
+            chunk_sizes: HashMap::new(),
+            path_table: HashMap::new(),
+            last_inode: files + 1,
+            free_inodes: vec![7, 9],
+            trash: HashMap::new(),
+            uploads: HashMap::new(),
+            checksums: HashMap::new(),
+            version: 3,
+            format: IndexFormat::Json,
+        };
+        let now = SystemTime::now();
+
+        for ino in 2..files + 2 {
+            let name = format!("dir-{}/file-{}.bin", ino % 100, ino);
+            let attr = FileAttr {
+                ino,
+                size: ino * 1000,
+                blocks: (ino * 1000).div_ceil(512),
+                atime: now,
+                mtime: UNIX_EPOCH + Duration::from_nanos(ino * 1_000_000_007),
+                ctime: now,
+                crtime: UNIX_EPOCH + Duration::new(ino, 500),
+                kind: FileType::RegularFile,
+                perm: 0o644,
+                nlink: 1,
+                uid: 1000,
+                gid: 1000,
+                rdev: 0,
+                blksize: 512,
+                flags: 0,
+            };
+
+            index.lookup_table.insert(name.clone(), attr);
+            index.path_table.insert(ino, name);
+            index.chunk_table.insert(ino, vec![ino * 2, ino * 2 + 1]);
+            index.checksums.insert(ino * 2, ino.wrapping_mul(31));
+        }
+
+        index.uploads.insert(
+            3,
+            PartialUpload {
+                hash: 42,
+                chunks: vec![Some(5), None],
+            },
+        );
+
+        index
+    }
+
+    #[test]
+    fn binary_indexes_round_trip_smaller_than_json() {
+        let mut index = tree(50_000);
+        let json = index.to_bytes().unwrap();
+
+        index.format = IndexFormat::Binary;
+        let binary = index.to_bytes().unwrap();
+        let read = Index::from_bytes(&binary).unwrap();
+
+        assert!(binary.starts_with(BINARY_MAGIC));
+        assert!(binary.len() < json.len() / 2);
+
+        assert_eq!(read.format, IndexFormat::Binary);
+        assert_eq!((read.version, read.last_inode), (3, 50_001));
+        assert_eq!(read.fingerprints(), index.fingerprints());
+        assert_eq!(read.path_table, index.path_table);
+        assert_eq!(read.free_inodes, index.free_inodes);
+        assert_eq!(read.uploads[&3].chunks, vec![Some(5), None]);
+
+        for (name, attr) in &index.lookup_table {
+            let read = read.lookup_table[name];
+            assert_eq!(
+                (read.mtime, read.crtime, read.kind, read.perm),
+                (attr.mtime, attr.crtime, attr.kind, attr.perm)
+            );
+        }
+
+        // Written back in the format it was read in
+        assert_eq!(read.to_bytes().unwrap().len(), binary.len());
+    }
+
+    #[test]
+    fn binary_indexes_read_back_exactly_what_was_written() {
+        let mut index = tree(3);
+        index.format = IndexFormat::Binary;
+
+        let odd = encode_name(OsStr::from_bytes(b"caf\xe9/\xff\xfe.bin"));
+        let attr = index.lookup_table["dir-2/file-2.bin"];
+        index
+            .lookup_table
+            .insert(odd.clone(), FileAttr { ino: 8, ..attr });
+        index.path_table.insert(8, odd.clone());
+        index.chunk_table.insert(8, vec![80, 81, 82]);
+        index.chunk_sizes.insert(8, 1 << 20);
+
+        // Gone from the tree but with its chunks kept
+        let trashed = index.lookup_table.remove("dir-3/file-3.bin").unwrap();
+        index
+            .trash
+            .insert(3, (encode_name(OsStr::from_bytes(b"\x80old")), trashed));
+        index.uploads.insert(
+            4,
+            PartialUpload {
+                hash: u64::MAX,
+                chunks: vec![None, Some(1 << 40), None],
+            },
+        );
+        index.free_inodes = vec![10, 6, 12];
+
+        let read = Index::from_bytes(&index.to_bytes().unwrap()).unwrap();
+
+        // Everything serde sees, which is every stored field
+        assert_eq!(
+            serde_json::to_value(&read).unwrap(),
+            serde_json::to_value(&index).unwrap()
+        );
+        assert_eq!(read.format, IndexFormat::Binary);
+        assert_eq!(
+            decode_name(&read.trash[&3].0).as_bytes(),
+            b"\x80old".as_slice()
+        );
+        assert!(read
+            .lookup_table
+            .keys()
+            .any(|name| decode_name(name).as_bytes() == b"caf\xe9/\xff\xfe.bin"));
+    }
+
+    #[test]
+    fn unknown_binary_versions_are_refused() {
+        let mut index = tree(1);
+        index.format = IndexFormat::Binary;
+        let mut bytes = index.to_bytes().unwrap();
+
+        bytes[BINARY_MAGIC.len()] = 2;
+        let error = Index::from_bytes(&bytes).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        bytes[BINARY_MAGIC.len()] = 1;
+        bytes.pop();
+        assert!(Index::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn names_survive_encoding_whatever_their_bytes() {
+        let names: [&[u8]; 5] = [
+            b"plain.txt",
+            "caf\u{e9}.txt".as_bytes(),
+            b"latin1-caf\xe9.txt",
+            b"\xff\xfe\x80",
+            "\u{10FFFF}\u{10FE41}".as_bytes(),
+        ];
+
+        for name in names {
+            let encoded = encode_name(OsStr::from_bytes(name));
+            assert_eq!(decode_name(&encoded).as_bytes(), name, "{:?}", encoded);
+        }
+
+        // UTF-8 names are stored as they are, so older indexes still read the same
+        assert_eq!(encode_name(OsStr::new("caf\u{e9}.txt")), "caf\u{e9}.txt");
+        assert_ne!(
+            encode_name(OsStr::from_bytes(b"\xff")),
+            encode_name(OsStr::from_bytes("\u{10FFFF}\u{10FEFF}".as_bytes()))
+        );
+    }
+}
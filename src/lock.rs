@@ -0,0 +1,205 @@
+use libc::{c_int, EAGAIN, EINVAL, F_RDLCK, F_UNLCK, F_WRLCK};
+use std::collections::HashMap;
+use std::mem;
+
+// A byte range from `start` to `end` inclusive. flock() locks arrive as
+// locks over the whole file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lock {
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+    pub pid: u32,
+}
+
+impl Lock {
+    fn overlaps(&self, other: &Lock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other)
+            && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+// Advisory locks per inode, they only keep out other lockers
+#[derive(Default)]
+pub struct LockTable {
+    locks: HashMap<u64, Vec<Lock>>,
+}
+
+impl LockTable {
+    // The first lock held by another owner that stands in the way of `lock`
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks
+            .get(&ino)?
+            .iter()
+            .find(|held| held.conflicts(lock))
+            .copied()
+    }
+
+    // Takes the range for the owner, or gives it up with F_UNLCK. Whatever the
+    // owner already held over the range is replaced, so locks can be converted.
+    pub fn set(&mut self, ino: u64, lock: Lock) -> Result<(), c_int> {
+        if ![F_RDLCK, F_WRLCK, F_UNLCK].contains(&lock.typ) || lock.start > lock.end {
+            return Err(EINVAL);
+        }
+
+        if lock.typ != F_UNLCK && self.conflict(ino, &lock).is_some() {
+            return Err(EAGAIN);
+        }
+
+        let mut held = Vec::new();
+
+        for old in self.locks.remove(&ino).unwrap_or_default() {
+            if old.owner != lock.owner || !old.overlaps(&lock) {
+                held.push(old);
+                continue;
+            }
+
+            // Only the parts sticking out on either side survive
+            if old.start < lock.start {
+                held.push(Lock {
+                    end: lock.start - 1,
+                    ..old
+                });
+            }
+
+            if old.end > lock.end {
+                held.push(Lock {
+                    start: lock.end + 1,
+                    ..old
+                });
+            }
+        }
+
+        if lock.typ != F_UNLCK {
+            held.push(lock);
+        }
+
+        if !held.is_empty() {
+            self.locks.insert(ino, held);
+        }
+
+        Ok(())
+    }
+
+    // Drops everything the owner holds on the inode, as happens when it closes the file
+    pub fn release(&mut self, ino: u64, owner: u64) -> bool {
+        let Some(held) = self.locks.get_mut(&ino) else {
+            return false;
+        };
+
+        let before = held.len();
+        held.retain(|lock| lock.owner != owner);
+        let released = held.len() != before;
+
+        if held.is_empty() {
+            self.locks.remove(&ino);
+        }
+
+        released
+    }
+
+    // Retries blocked requests until none of them get any further, handing back
+    // each one that got an answer along with it
+    pub fn wake<W>(&mut self, waiters: &mut Vec<(u64, Lock, W)>) -> Vec<(W, Result<(), c_int>)> {
+        let mut answered = Vec::new();
+
+        loop {
+            let mut granted = false;
+
+            for (ino, lock, waiter) in mem::take(waiters) {
+                match self.set(ino, lock) {
+                    Ok(()) => {
+                        answered.push((waiter, Ok(())));
+                        granted = true;
+                    }
+                    Err(EAGAIN) => waiters.push((ino, lock, waiter)),
+                    Err(e) => answered.push((waiter, Err(e))),
+                }
+            }
+
+            if !granted {
+                return answered;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> Lock {
+        Lock {
+            owner,
+            start,
+            end,
+            typ,
+            pid: owner as u32,
+        }
+    }
+
+    #[test]
+    fn only_overlapping_locks_of_other_owners_conflict() {
+        let mut table = LockTable::default();
+        table.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+
+        assert_eq!(table.set(1, lock(2, 50, 150, F_RDLCK)), Err(EAGAIN));
+        assert_eq!(
+            table.conflict(1, &lock(2, 50, 150, F_RDLCK)),
+            Some(lock(1, 0, 99, F_WRLCK))
+        );
+        assert_eq!(table.set(1, lock(2, 100, 150, F_WRLCK)), Ok(()));
+        assert_eq!(table.set(2, lock(2, 0, 99, F_WRLCK)), Ok(()));
+
+        // The owner's own lock never gets in its way, it's converted instead
+        assert_eq!(table.set(1, lock(1, 0, 99, F_RDLCK)), Ok(()));
+        assert_eq!(table.set(1, lock(3, 0, 49, F_RDLCK)), Ok(()));
+    }
+
+    #[test]
+    fn unlocking_the_middle_keeps_both_ends() {
+        let mut table = LockTable::default();
+        table.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+        table.set(1, lock(1, 40, 59, F_UNLCK)).unwrap();
+
+        assert_eq!(table.conflict(1, &lock(2, 40, 59, F_WRLCK)), None);
+        assert_eq!(
+            table.conflict(1, &lock(2, 39, 39, F_RDLCK)),
+            Some(lock(1, 0, 39, F_WRLCK))
+        );
+        assert_eq!(
+            table.conflict(1, &lock(2, 60, 60, F_RDLCK)),
+            Some(lock(1, 60, 99, F_WRLCK))
+        );
+        assert_eq!(table.set(1, lock(1, 50, 40, F_WRLCK)), Err(EINVAL));
+    }
+
+    #[test]
+    fn waiters_are_granted_once_the_range_frees_up() {
+        let mut table = LockTable::default();
+        table.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+
+        let mut waiters = vec![
+            (1, lock(2, 0, 9, F_WRLCK), "second"),
+            (1, lock(3, 0, 9, F_RDLCK), "third"),
+        ];
+        assert!(table.wake(&mut waiters).is_empty());
+        assert_eq!(waiters.len(), 2);
+
+        // Only one of them can have the range, the other keeps waiting on it
+        assert!(table.release(1, 1));
+        assert_eq!(table.wake(&mut waiters), vec![("second", Ok(()))]);
+        assert_eq!(waiters.len(), 1);
+
+        table.set(1, lock(2, 0, 9, F_UNLCK)).unwrap();
+        assert_eq!(table.wake(&mut waiters), vec![("third", Ok(()))]);
+        assert!(waiters.is_empty());
+        assert!(!table.release(1, 2));
+    }
+}
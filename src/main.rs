@@ -1,337 +1,3571 @@
+mod backend;
+mod bench;
+mod cache;
+mod daemon;
+mod dryrun;
+mod export;
+mod fsck;
+mod gc;
+mod index;
+mod lock;
+mod messages;
+mod metrics;
+mod mirror;
+mod profile;
+mod progress;
+mod retry;
+mod shard;
+mod shared;
+mod sparse;
+#[cfg(test)]
+mod testing;
+#[cfg(test)]
+mod tests;
+mod throttle;
+mod trash;
+
+use backend::{ChunkId, DirBackend, MemBackend, StorageBackend};
+use cache::CacheBackend;
+use clap::{command, value_parser, Arg, ArgAction, ArgMatches, Command};
+use daemon::PidFile;
+use dryrun::DryRunBackend;
+use fuser::consts::{FUSE_ATOMIC_O_TRUNC, FUSE_FLOCK_LOCKS, FUSE_POSIX_LOCKS};
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    Request,
+    BackgroundSession, FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr,
+    ReplyBmap, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyLock, ReplyStatfs, Request,
+    Session, TimeOrNow,
+};
+use index::{decode_name, encode_name, Index, IndexFormat, PartialUpload};
+use libc::{
+    c_int, EACCES, EAGAIN, EEXIST, EFBIG, EINVAL, EIO, EISDIR, EMFILE, ENOENT, ENOSPC, ENOTDIR,
+    ENOTTY, EOPNOTSUPP, EPERM, EROFS, F_OK, F_UNLCK, O_ACCMODE, O_RDONLY, O_TRUNC, O_WRONLY, R_OK,
+    W_OK, X_OK,
 };
-use libc::ENOENT;
-use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::time::{Duration, UNIX_EPOCH};
+use lock::{Lock, LockTable};
+use metrics::{Metrics, MetricsBackend};
+use mirror::MirrorBackend;
+use profile::{Profile, ProfileBackend, Timer};
+use progress::{Progress, ProgressHook, Tracker};
+use retry::RetryBackend;
+use shard::ShardBackend;
+use shared::SharedFS;
+use sparse::SparseBackend;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, ErrorKind, IsTerminal};
+use std::net::{SocketAddr, TcpListener};
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use std::{mem, process, ptr, thread};
+use throttle::ThrottleBackend;
 
 const TTL: Duration = Duration::from_secs(1); // 1 second
 
-const ROOT_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 502,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
-};
+const DEFAULT_MAX_FILE_SIZE: u64 = 1 << 30; // 1 GiB
+
+const DEFAULT_CONCURRENCY: usize = 4;
+
+const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024; // Discord's attachment limit without boosts
+
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+
+const NEGATIVE_LOOKUP_TTL: Duration = TTL;
+
+// Expired misses are only swept once this many have piled up
+const NEGATIVE_LOOKUP_LIMIT: usize = 1024;
+
+const CHUNK_CACHE_SIZE: usize = 4; // chunks, not bytes
+
+const DEFAULT_READAHEAD: usize = 2; // chunks
+
+// _IO('D', 1): upload the file's pending changes right away, see examples/flush.rs
+const IOCTL_FLUSH: u32 = 0x4401;
+
+// _IOR('D', 2, u64): how many chunks a flush of the file would upload
+const IOCTL_PENDING_CHUNKS: u32 = 0x8008_4402;
+
+// _IOR('f', 1, long) and _IOW('f', 2, long), what lsattr and chattr use
+const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+
+// chattr +a: the file can only be appended to. It's kept in FileAttr::flags,
+// so it's saved with the index.
+const FS_APPEND_FL: u32 = 0x20;
+
+const ROOT_INO: u64 = 1;
+
+// What tmpfs counts per directory entry
+const DIRENT_SIZE: u64 = 20;
 
-struct FS {
+const DEFAULT_ROOT_MODE: u16 = 0o755;
+
+// Merges with other mounts' saves before giving up on saving over them
+const INDEX_SAVE_ATTEMPTS: usize = 3;
+
+// For files added without a mode, like the samples of a fresh filesystem.
+// Created files get theirs from create's mode and umask.
+const DEFAULT_FILE_MODE: u16 = 0o644;
+
+struct FS<B: StorageBackend> {
+    backend: B,
     lookup_table: HashMap<String, FileAttr>,
+    chunk_table: HashMap<u64, Vec<ChunkId>>,
+    chunk_sizes: HashMap<u64, usize>,
     data_table: HashMap<u64, Vec<u8>>,
+    chunk_cache: HashMap<ChunkId, Arc<Vec<u8>>>,
+    chunk_cache_order: VecDeque<ChunkId>,
+    dirty: HashSet<u64>,
+    dirty_from: HashMap<u64, u64>,
     path_table: HashMap<u64, String>,
+    lookup_counts: HashMap<u64, u64>,
+    negative_lookups: HashMap<(u64, String), Instant>,
+    handles: HashMap<u64, Handle>,
+    next_fh: u64,
+    max_open_files: Option<usize>,
+    locks: LockTable,
+    lock_waiters: Vec<(u64, Lock, ReplyEmpty)>,
     last_inode: u64,
+    free_inodes: Vec<u64>,
+    total_size: u64,
+    max_file_size: u64,
+    max_dirty: Option<u64>,
+    capacity: Option<u64>,
+    concurrency: usize,
+    chunk_size: usize,
+    readahead: usize,
+    #[cfg(test)]
+    size_updates: usize,
+    read_only: bool,
+    verify: bool,
+    atime: AtimePolicy,
+    index_path: Option<PathBuf>,
+    index_format: IndexFormat,
+    refresh_interval: Option<Duration>,
+    last_refresh: Instant,
+    checkpoint_interval: Option<Duration>,
+    index_dirty: bool,
+    unsaved_deletes: Vec<ChunkId>,
+    use_trash: bool,
+    trash: HashMap<u64, (String, FileAttr)>,
+    // Files unlinked while the kernel still refers to them, by inode
+    unlinked: HashMap<u64, FileAttr>,
+    uploads: HashMap<u64, PartialUpload>,
+    checksums: HashMap<ChunkId, u64>,
+    index_version: u64,
+    synced: HashMap<String, u64>,
+    missing_chunks: HashSet<ChunkId>,
+    metrics: Arc<Metrics>,
+    profile: Option<Arc<Profile>>,
+    progress: Option<ProgressHook>,
 }
 
-impl Default for FS {
-    fn default() -> Self {
+impl<B: StorageBackend> FS<B> {
+    fn new(backend: B) -> Self {
         let mut fs = FS {
+            backend,
             lookup_table: HashMap::new(),
+            chunk_table: HashMap::new(),
+            chunk_sizes: HashMap::new(),
             data_table: HashMap::new(),
+            chunk_cache: HashMap::new(),
+            chunk_cache_order: VecDeque::new(),
+            dirty: HashSet::new(),
+            dirty_from: HashMap::new(),
             path_table: HashMap::new(),
+            lookup_counts: HashMap::new(),
+            negative_lookups: HashMap::new(),
+            handles: HashMap::new(),
+            next_fh: 1,
+            max_open_files: None,
+            locks: LockTable::default(),
+            lock_waiters: Vec::new(),
             last_inode: 1,
+            free_inodes: Vec::new(),
+            total_size: 0,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_dirty: None,
+            capacity: None,
+            concurrency: DEFAULT_CONCURRENCY,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            readahead: DEFAULT_READAHEAD,
+            #[cfg(test)]
+            size_updates: 0,
+            read_only: false,
+            verify: true,
+            atime: AtimePolicy::Relatime,
+            index_path: None,
+            index_format: IndexFormat::Json,
+            refresh_interval: None,
+            last_refresh: Instant::now(),
+            checkpoint_interval: None,
+            index_dirty: false,
+            unsaved_deletes: Vec::new(),
+            use_trash: false,
+            trash: HashMap::new(),
+            unlinked: HashMap::new(),
+            uploads: HashMap::new(),
+            checksums: HashMap::new(),
+            index_version: 0,
+            synced: HashMap::new(),
+            missing_chunks: HashSet::new(),
+            metrics: Arc::default(),
+            profile: None,
+            progress: None,
         };
 
-        fs.lookup_table.insert(".".to_string(), ROOT_DIR_ATTR);
-        fs.path_table.insert(0, ".".to_string());
+        fs.lookup_table
+            .insert(".".to_string(), root_attr(DEFAULT_ROOT_MODE));
+        fs.path_table.insert(ROOT_INO, ".".to_string());
 
         fs
     }
-}
 
-impl FS {
-    fn add_file(&mut self, name: &str, data: &[u8]) -> (u64, FileAttr) {
-        let new_inode = self.last_inode + 1;
+    // The root belongs to whoever mounts it, whatever the index says
+    fn configure_root(&mut self, perm: Option<u16>) {
+        let Some(root) = self.lookup_table.get_mut(".") else {
+            return;
+        };
+
+        let current = root_attr(root.perm);
+        root.uid = current.uid;
+        root.gid = current.gid;
+
+        if let Some(perm) = perm {
+            root.perm = perm;
+        }
+    }
+
+    // An existing file of the same name is never replaced, its chunks would be lost
+    fn add_file(&mut self, name: &str, data: &[u8]) -> Result<(u64, FileAttr), c_int> {
+        if self.lookup_table.contains_key(name) {
+            return Err(EEXIST);
+        }
+
+        let new_inode = self.allocate_inode();
+        let owner = Caller::mounter();
+        let now = SystemTime::now();
         let attr = FileAttr {
             ino: new_inode,
             size: data.len() as u64,
-            blocks: (data.len() as u64 / 512) + 1,
-            atime: UNIX_EPOCH,
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
-            crtime: UNIX_EPOCH,
+            blocks: blocks(data.len() as u64),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
             kind: FileType::RegularFile,
-            perm: 0o755,
+            perm: DEFAULT_FILE_MODE,
             nlink: 2,
-            uid: 501,
-            gid: 20,
+            uid: owner.uid,
+            gid: owner.gid,
             rdev: 0,
             flags: 0,
             blksize: 512,
         };
 
         self.lookup_table.insert(name.to_string(), attr);
+        self.negative_lookups.remove(&(ROOT_INO, name.to_string()));
+        self.chunk_table.insert(new_inode, Vec::new());
         self.data_table.insert(new_inode, data.to_vec());
+        self.dirty.insert(new_inode);
+        self.dirty_from.insert(new_inode, 0);
+        self.update_dirty_bytes();
         self.path_table.insert(new_inode, name.to_string());
 
-        self.last_inode = new_inode;
+        self.total_size += data.len() as u64;
+        self.check_fs_size();
 
-        (new_inode, attr)
+        Ok((new_inode, attr))
     }
 
-    fn update_fs_size(&mut self) {
-        let mut size = 0;
+    // Copies the regular files directly inside `dir`, keeping their mode, owner and mtime
+    fn import_dir(&mut self, dir: &Path) -> io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
 
-        for v in self.lookup_table.values() {
-            if let Some(data) = self.data_table.get(&v.ino) {
-                size += data.len();
+            if !metadata.is_file() {
+                continue;
             }
+
+            let name = encode_name(&entry.file_name());
+            let (_, attr) = self
+                .add_file(&name, &std::fs::read(entry.path())?)
+                .map_err(io::Error::from_raw_os_error)?;
+
+            let attr = FileAttr {
+                perm: (metadata.mode() & 0o7777) as u16,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                mtime: metadata.modified()?,
+                ..attr
+            };
+
+            self.lookup_table.insert(name, attr);
         }
 
-        self.lookup_table.insert(
-            ".".to_string(),
-            FileAttr {
-                size: size as u64,
-                blocks: (size as u64 / 512) + 1,
-                ..*self.lookup_table.get(".").unwrap()
-            },
-        );
+        Ok(())
     }
-}
 
-impl Filesystem for FS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if parent != 1 {
-            reply.error(ENOENT);
-            return;
+    fn restore_index(&mut self, index: Index) {
+        // Their data only lived in memory, so there's nothing left to finish them with
+        for (ino, upload) in &index.uploads {
+            eprintln!("discarding an unfinished upload of inode {}", ino);
+            self.delete_chunks(&upload.uploaded());
         }
 
-        let Some(attr) = self.lookup_table.get(name.to_str().unwrap()) else {
-            reply.error(ENOENT);
-            return;
-        };
+        self.synced = index.fingerprints();
+        self.index_version = index.version;
+        self.index_format = index.format;
+        self.lookup_table = index.lookup_table;
+        self.chunk_table = index.chunk_table;
+        self.chunk_sizes = index.chunk_sizes;
+        self.path_table = index.path_table;
+        self.last_inode = index.last_inode;
+        self.free_inodes = index.free_inodes;
+        self.trash = index.trash;
+        self.checksums = index.checksums;
+        self.data_table.clear();
+        self.dirty.clear();
+        self.dirty_from.clear();
+        self.total_size = self.compute_fs_size();
+    }
 
-        reply.entry(&TTL, attr, 0)
+    // With --checkpoint-interval, changes only mark the index for the next checkpoint
+    fn save_index(&mut self) {
+        self.index_dirty = true;
+
+        if self.checkpoint_interval.is_none() {
+            self.write_index();
+        }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        for v in self.lookup_table.values() {
-            if v.ino == ino {
-                reply.attr(&TTL, v);
+    fn checkpoint(&mut self) {
+        if self.index_dirty {
+            self.write_index();
+        }
+    }
 
-                return;
-            }
+    // Chunks the saved index may still reference wait until it no longer does
+    fn delete_after_save(&mut self, chunks: Vec<ChunkId>) {
+        if self.index_dirty {
+            self.unsaved_deletes.extend(chunks);
+        } else {
+            self.delete_chunks(&chunks);
+        }
+    }
+
+    fn write_index(&mut self) {
+        if let Err(e) = self.save_shared_index() {
+            eprintln!("failed to save index to the backend: {}", e);
         }
 
-        reply.error(ENOENT);
+        if let Some(path) = &self.index_path {
+            // Ahead of the backend's copy while that one couldn't be saved
+            let version = self.index_version + u64::from(self.index_dirty);
+
+            if let Err(e) = self.to_index(version).save(path) {
+                eprintln!("failed to save index to {}: {}", path.display(), e);
+            }
+        }
     }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        _size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        let Some(data) = self.data_table.get(&ino) else {
-            reply.error(ENOENT);
-            return;
-        };
+    // The backend's copy is shared, so a version saved by another mount since
+    // we last looked is merged in, and ours only replaces the copy it was
+    // merged with. One that keeps changing is left alone rather than overwritten.
+    fn save_shared_index(&mut self) -> io::Result<()> {
+        for _ in 0..INDEX_SAVE_ATTEMPTS {
+            let stored = self.backend.load_index()?;
+
+            match stored.as_deref().map(Index::from_bytes).transpose() {
+                Ok(Some(stored)) if stored.version != self.index_version => {
+                    eprintln!(
+                        "index changed underneath (version {}, expected {}), merging",
+                        stored.version, self.index_version
+                    );
+                    self.merge_index(stored);
+                }
+                Ok(_) => {}
+                // Unreadable, ours is the best there is
+                Err(e) => eprintln!("failed to read the stored index: {}, replacing it", e),
+            }
+
+            let index = self.to_index(self.index_version + 1);
 
-        let data = &data.as_slice()[offset as usize..];
+            if !self
+                .backend
+                .save_index_if(stored.as_deref(), &index.to_bytes()?)?
+            {
+                continue;
+            }
+
+            self.index_version = index.version;
+            self.synced = index.fingerprints();
+            self.index_dirty = false;
+
+            let unsaved = mem::take(&mut self.unsaved_deletes);
+            self.delete_chunks(&unsaved);
+
+            return Ok(());
+        }
 
-        reply.data(data);
+        Err(io::Error::other(
+            "the stored index kept changing underneath, not saving over it",
+        ))
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        if ino != 1 {
-            reply.error(ENOENT);
-            return;
+    fn to_index(&self, version: u64) -> Index {
+        Index {
+            lookup_table: self.lookup_table.clone(),
+            chunk_table: self.chunk_table.clone(),
+            chunk_sizes: self.chunk_sizes.clone(),
+            path_table: self.path_table.clone(),
+            last_inode: self.last_inode,
+            free_inodes: self.free_inodes.clone(),
+            trash: self.trash.clone(),
+            uploads: self.uploads.clone(),
+            checksums: self.checksums.clone(),
+            version,
+            format: self.index_format,
         }
+    }
+
+    fn fingerprint(&self, name: &str) -> Option<u64> {
+        let attr = self.lookup_table.get(name)?;
+        let chunks = self.chunk_table.get(&attr.ino);
+
+        Some(index::fingerprint(attr, chunks, &self.checksums))
+    }
+
+    fn is_dirty(&self, name: &str) -> bool {
+        self.lookup_table
+            .get(name)
+            .is_some_and(|attr| self.dirty.contains(&attr.ino))
+    }
+
+    // Three-way merge against the version we last synced with: entries only
+    // the other mount changed are taken from `theirs`, entries we changed
+    // keep our version whatever they did
+    fn merge_index(&mut self, mut theirs: Index) {
+        let fingerprints = theirs.fingerprints();
+
+        let removed: Vec<String> = self
+            .synced
+            .keys()
+            .filter(|name| !theirs.lookup_table.contains_key(*name))
+            .filter(|name| self.fingerprint(name) == self.synced.get(*name).copied())
+            .filter(|name| !self.is_dirty(name))
+            .cloned()
+            .collect();
+
+        for name in removed {
+            let Some(attr) = self.lookup_table.remove(&name) else {
+                continue;
+            };
 
-        let mut entries: Vec<(u64, FileType, &str)> = vec![(1, FileType::Directory, "..")];
+            self.path_table.remove(&attr.ino);
+            self.data_table.remove(&attr.ino);
+            self.chunk_sizes.remove(&attr.ino);
 
-        for (k, v) in &self.lookup_table {
-            entries.append(&mut vec![(v.ino, FileType::RegularFile, k.as_str())]);
+            // Deleting the chunks was up to the mount that removed the file
+            if let Some(chunks) = self.chunk_table.remove(&attr.ino) {
+                self.evict_chunks(&chunks);
+            }
         }
 
-        println!("{:?}", entries);
+        let names: Vec<String> = theirs.lookup_table.keys().cloned().collect();
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                break;
+        for name in names {
+            let mut attr = theirs.lookup_table[&name];
+            let base = self.synced.get(&name).copied();
+
+            // The root's attributes are this mount's own
+            if attr.ino == ROOT_INO || base == Some(fingerprints[&name]) {
+                continue;
+            }
+
+            let ours = self.fingerprint(&name);
+
+            // Unflushed writes don't show in the fingerprint until they're uploaded
+            if ours != base || self.is_dirty(&name) {
+                if ours.is_some() && ours != Some(fingerprints[&name]) {
+                    eprintln!(
+                        "{} was changed by another mount as well, keeping ours",
+                        name
+                    );
+                }
+
+                continue;
+            }
+
+            // Both mounts handed out the same next inode, theirs moves past both
+            if self
+                .path_table
+                .get(&attr.ino)
+                .is_some_and(|local| *local != name)
+            {
+                let ino = self.last_inode.max(theirs.last_inode) + 1;
+                eprintln!(
+                    "{} from another mount reuses inode {}, moving it to {}",
+                    name, attr.ino, ino
+                );
+
+                theirs.renumber(attr.ino, ino);
+                attr.ino = ino;
             }
+
+            self.take_entry(name, attr, &theirs);
         }
-        reply.ok();
+
+        for (id, sum) in &theirs.checksums {
+            self.checksums.entry(*id).or_insert(*sum);
+        }
+
+        for (ino, entry) in theirs.trash {
+            self.trash.entry(ino).or_insert(entry);
+        }
+
+        self.index_version = theirs.version;
+        self.synced = fingerprints;
+        self.negative_lookups.clear();
+        self.last_inode = self.last_inode.max(theirs.last_inode);
+        self.total_size = self.compute_fs_size();
     }
 
-    fn mknod(
-        &mut self,
-        _req: &Request<'_>,
-        _parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _rdev: u32,
-        reply: ReplyEntry,
-    ) {
-        let (_, attr) = self.add_file(name.to_str().unwrap(), &[0]);
+    // Replaces our entry for `name` with the one in `index`, chunks and all
+    fn take_entry(&mut self, name: String, attr: FileAttr, index: &Index) {
+        let chunks = index
+            .chunk_table
+            .get(&attr.ino)
+            .cloned()
+            .unwrap_or_default();
+
+        for id in &chunks {
+            if let Some(&sum) = index.checksums.get(id) {
+                self.checksums.insert(*id, sum);
+            }
+        }
+
+        if let Some(old) = self.chunk_table.insert(attr.ino, chunks.clone()) {
+            if old != chunks {
+                self.data_table.remove(&attr.ino);
+                self.evict_chunks(&old);
+            }
+        }
+
+        match index.chunk_sizes.get(&attr.ino) {
+            Some(&size) => self.chunk_sizes.insert(attr.ino, size),
+            None => self.chunk_sizes.remove(&attr.ino),
+        };
+
+        // The name moved to another inode, what hung off the old one goes with it
+        if let Some(old) = self.lookup_table.get(&name).map(|old| old.ino) {
+            if old != attr.ino {
+                self.path_table.remove(&old);
+                self.data_table.remove(&old);
+                self.chunk_table.remove(&old);
+                self.chunk_sizes.remove(&old);
+            }
+        }
 
-        reply.entry(&TTL, &attr, 0)
+        self.free_inodes.retain(|&ino| ino != attr.ino);
+        self.path_table.insert(attr.ino, name.clone());
+        self.lookup_table.insert(name, attr);
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, _parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        let Some(_) = self.lookup_table.remove(name.to_str().unwrap()) else {
-            reply.error(ENOENT);
-            return;
+    fn maybe_refresh(&mut self) {
+        if self
+            .refresh_interval
+            .is_some_and(|interval| self.last_refresh.elapsed() >= interval)
+        {
+            self.refresh_index();
+        }
+    }
+
+    // Takes in what other mounts of the same store saved since. Files with
+    // unflushed changes are left alone, the next flush overwrites them anyway.
+    fn refresh_index(&mut self) {
+        self.last_refresh = Instant::now();
+
+        // The backend's copy is the shared one, a local --index file only this mount writes
+        let mut index = match Index::fetch(&self.backend, None) {
+            Ok(Some(index)) => index,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("failed to refresh the index: {}", e);
+                return;
+            }
         };
 
-        reply.ok();
+        let gone: Vec<(String, u64)> = self
+            .lookup_table
+            .iter()
+            .filter(|(name, attr)| {
+                !index.lookup_table.contains_key(*name) && !self.dirty.contains(&attr.ino)
+            })
+            .map(|(name, attr)| (name.clone(), attr.ino))
+            .collect();
+
+        for (name, ino) in gone {
+            self.lookup_table.remove(&name);
+            self.path_table.remove(&ino);
+            self.data_table.remove(&ino);
+
+            // Deleting the chunks was up to the mount that removed the file
+            if let Some(chunks) = self.chunk_table.remove(&ino) {
+                self.evict_chunks(&chunks);
+            }
+        }
+
+        let fingerprints = index.fingerprints();
+
+        for (name, attr) in mem::take(&mut index.lookup_table) {
+            let local = self.lookup_table.get(&name).map(|local| local.ino);
+
+            if self.dirty.contains(&attr.ino) || local.is_some_and(|ino| self.dirty.contains(&ino))
+            {
+                continue;
+            }
+
+            self.take_entry(name, attr, &index);
+        }
+
+        self.checksums.extend(index.checksums);
+        self.trash.extend(index.trash);
+        self.index_version = index.version;
+        self.synced = fingerprints;
+        self.negative_lookups.clear();
+        self.last_inode = self.last_inode.max(index.last_inode);
+        self.total_size = self.compute_fs_size();
     }
 
-    fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        if !self.data_table.contains_key(&_ino) {
-            reply.error(ENOENT);
+    // Pulls a file's chunks into data_table unless it is already loaded
+    fn load_data(&mut self, ino: u64) -> Result<(), c_int> {
+        if self.data_table.contains_key(&ino) {
+            self.metrics.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
 
-            return;
+        self.metrics.cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        // The uploaded chunks are older than the lost buffer, serving them would be stale
+        if self.dirty.contains(&ino) {
+            eprintln!("unflushed data of inode {} is no longer buffered", ino);
+            return Err(EIO);
+        }
+
+        let Some(chunks) = self.chunk_table.get(&ino).cloned() else {
+            return Err(ENOENT);
+        };
+
+        if chunks.iter().any(|id| self.missing_chunks.contains(id)) {
+            return Err(EIO);
+        }
+
+        let results =
+            backend::parallel(&chunks, self.concurrency, |id| self.backend.get_chunk(*id));
+
+        let mut data = Vec::new();
+
+        for (id, result) in chunks.iter().zip(results) {
+            let chunk = result.map_err(|e| self.fetch_error(ino, *id, e))?;
+            self.verify_chunk(ino, *id, &chunk)?;
+            data.extend_from_slice(&chunk);
         }
 
-        self.update_fs_size();
+        self.data_table.insert(ino, data);
 
-        reply.opened(0, _flags as u32);
+        Ok(())
     }
 
-    fn write(
+    // Serves a window of a file that isn't buffered by fetching just the chunks it
+    // overlaps, along with the `prefetch` chunks after it
+    fn read_chunks(
         &mut self,
-        _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: fuser::ReplyWrite,
-    ) {
-        let Some(path) = self.path_table.get(&ino) else {
-            reply.error(ENOENT);
-            return;
-        };
+        file_size: u64,
+        offset: u64,
+        size: u32,
+        prefetch: usize,
+    ) -> Result<Vec<u8>, c_int> {
+        let end = offset.saturating_add(size as u64).min(file_size);
 
-        let Some(attrs) = self.lookup_table.get_mut(path) else {
-            reply.error(ENOENT);
-            return;
-        };
+        if offset >= end {
+            return Ok(Vec::new());
+        }
 
-        let Some(existing_data) = self.data_table.get_mut(&ino) else {
-            reply.error(ENOENT);
-            return;
+        let Some(chunks) = self.chunk_table.get(&ino).cloned() else {
+            return Err(ENOENT);
         };
 
-        let size = data.len();
+        let chunk_size = self
+            .chunk_sizes
+            .get(&ino)
+            .copied()
+            .unwrap_or(DEFAULT_CHUNK_SIZE) as u64;
+
+        let first = (offset / chunk_size) as usize;
+        let last = ((end - 1) / chunk_size) as usize;
 
-        for (i, b) in data.iter().enumerate() {
-            existing_data.insert(offset as usize + i, *b);
+        if last >= chunks.len() {
+            eprintln!("inode {} is larger than its chunks", ino);
+            return Err(EIO);
         }
 
-        if data.len() + offset as usize > attrs.size as usize {
-            attrs.size = (data.len() + offset as usize) as u64;
+        let ahead = (last + 1 + prefetch).min(chunks.len());
+        let missing: Vec<usize> = (first..ahead)
+            .filter(|&i| !self.chunk_cache.contains_key(&chunks[i]))
+            .filter(|&i| !self.missing_chunks.contains(&chunks[i]))
+            .collect();
+
+        // Reads that skip around fetch only their part of each chunk, which isn't
+        // worth caching. Sequential reads get whole chunks to read on from. A
+        // checksum covers a whole chunk, so only whole ones are verified.
+        let fetches: Vec<(usize, Option<Range<usize>>)> = missing
+            .into_iter()
+            .map(|i| {
+                if prefetch > 0 {
+                    return (i, None);
+                }
+
+                let chunk_start = i as u64 * chunk_size;
+                let chunk_len = (file_size - chunk_start).min(chunk_size);
+                let from = offset.max(chunk_start) - chunk_start;
+                let to = (end - chunk_start).min(chunk_len);
+                let whole = from == 0 && to == chunk_len;
+
+                (i, (!whole).then_some(from as usize..to as usize))
+            })
+            .collect();
+
+        // Fetched together, so the chunks read next arrive while this window is served
+        let results = backend::parallel(&fetches, self.concurrency, |(i, part)| match part {
+            Some(range) => self.backend.get_chunk_range(chunks[*i], range.clone()),
+            None => self.backend.get_chunk(chunks[*i]),
+        });
+
+        // Each with the offset into the chunk it starts at
+        let mut fetched = HashMap::new();
+
+        for ((i, part), result) in fetches.into_iter().zip(results) {
+            match (result, part) {
+                (Ok(data), Some(range)) => {
+                    fetched.insert(i, (Arc::new(data), range.start));
+                }
+                (Ok(chunk), None) => match self.verify_chunk(ino, chunks[i], &chunk) {
+                    Ok(()) => {
+                        let chunk = Arc::new(chunk);
+                        self.cache_chunk(chunks[i], chunk.clone());
+                        fetched.insert(i, (chunk, 0));
+                    }
+                    Err(e) if i <= last => return Err(e),
+                    Err(_) => {}
+                },
+                (Err(e), _) if i <= last => return Err(self.fetch_error(ino, chunks[i], e)),
+                // Prefetched chunks only matter once they're actually read, but
+                // one that's gone is remembered all the same
+                (Err(e), _) => {
+                    if e.kind() == ErrorKind::NotFound {
+                        self.fetch_error(ino, chunks[i], e);
+                    }
+                }
+            }
         }
 
-        self.update_fs_size();
+        let mut window = Vec::with_capacity((end - offset) as usize);
+
+        for (i, &id) in chunks.iter().enumerate().take(last + 1).skip(first) {
+            let (chunk, skipped) = match fetched.get(&i) {
+                Some((chunk, skipped)) => {
+                    self.metrics.cache_misses.fetch_add(1, Ordering::Relaxed);
+                    (chunk.clone(), *skipped)
+                }
+                None => (self.fetch_chunk(ino, id)?, 0),
+            };
+
+            let chunk_start = i as u64 * chunk_size + skipped as u64;
+            let from = (offset.max(chunk_start) - chunk_start) as usize;
+            let to = ((end - chunk_start) as usize).min(chunk.len());
+
+            if from < to {
+                window.extend_from_slice(&chunk[from..to]);
+            }
+        }
 
-        reply.written(size as u32);
+        Ok(window)
     }
 
-    fn flush(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        reply: fuser::ReplyEmpty,
-    ) {
-        if !self.data_table.contains_key(&ino) {
-            reply.error(ENOENT);
+    // The last few chunks read are kept, so sequential reads don't fetch one per call
+    fn fetch_chunk(&mut self, ino: u64, id: ChunkId) -> Result<Arc<Vec<u8>>, c_int> {
+        if let Some(chunk) = self.chunk_cache.get(&id) {
+            self.metrics.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(chunk.clone());
+        }
 
-            return;
+        // Asking again would only wait on the backend to say the same thing
+        if self.missing_chunks.contains(&id) {
+            return Err(EIO);
         }
 
-        self.update_fs_size();
+        self.metrics.cache_misses.fetch_add(1, Ordering::Relaxed);
 
-        reply.ok();
+        let chunk = self
+            .backend
+            .get_chunk(id)
+            .map_err(|e| self.fetch_error(ino, id, e))?;
+
+        self.verify_chunk(ino, id, &chunk)?;
+
+        let chunk = Arc::new(chunk);
+        self.cache_chunk(id, chunk.clone());
+
+        Ok(chunk)
     }
 
-    fn release(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        if !self.data_table.contains_key(&_ino) {
-            reply.error(ENOENT);
+    fn fetch_error(&mut self, ino: u64, id: ChunkId, e: io::Error) -> c_int {
+        eprintln!("failed to fetch chunk {} of inode {}: {}", id, ino, e);
 
-            return;
+        // The file exists, so a chunk it references going missing is lost data.
+        // The inode stays degraded: its other chunks are still served, this
+        // one fails straight away from now on.
+        match e.kind() {
+            ErrorKind::NotFound => {
+                if self.missing_chunks.insert(id) {
+                    eprintln!("inode {} is degraded, chunk {} is gone", ino, id);
+                }
+
+                EIO
+            }
+            _ => backend::errno(&e),
         }
+    }
 
-        self.update_fs_size();
+    // Catches chunks that come back truncated or altered since they were uploaded
+    fn verify_chunk(&self, ino: u64, id: ChunkId, chunk: &[u8]) -> Result<(), c_int> {
+        if !self.verify {
+            return Ok(());
+        }
 
-        reply.ok();
+        match self.checksums.get(&id) {
+            Some(&expected) if expected != backend::checksum(chunk) => {
+                eprintln!(
+                    "chunk {} of inode {} doesn't match its checksum ({} bytes)",
+                    id,
+                    ino,
+                    chunk.len()
+                );
+                Err(EIO)
+            }
+            _ => Ok(()),
+        }
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<std::time::SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<std::time::SystemTime>,
-        _chgtime: Option<std::time::SystemTime>,
-        _bkuptime: Option<std::time::SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        let path = &self.path_table[&ino];
-        let attr = self.lookup_table[path];
-        reply.attr(&TTL, &attr);
-    }
-}
+    // Room is left for a full readahead window on top of the chunks being read
+    fn cache_chunk(&mut self, id: ChunkId, chunk: Arc<Vec<u8>>) {
+        if self.chunk_cache.insert(id, chunk).is_some() {
+            return;
+        }
 
-fn main() {
-    let mut options = vec![
-        MountOption::RW,
-        MountOption::FSName("discordfs".to_string()),
-    ];
-    options.push(MountOption::AutoUnmount);
-    options.push(MountOption::AllowOther);
+        self.chunk_cache_order.push_back(id);
+
+        if self.chunk_cache_order.len() <= CHUNK_CACHE_SIZE + self.readahead {
+            return;
+        }
+
+        // Chunks of files that are still open go last, reads of other files
+        // shouldn't make an open handle fetch its data again. They're only
+        // pinned while some handle is open, and the cache stays bounded.
+        let pinned: HashSet<ChunkId> = self
+            .handles
+            .values()
+            .filter_map(|handle| self.chunk_table.get(&handle.ino))
+            .flatten()
+            .copied()
+            .collect();
+
+        while self.chunk_cache_order.len() > CHUNK_CACHE_SIZE + self.readahead {
+            let oldest = self
+                .chunk_cache_order
+                .iter()
+                .position(|cached| !pinned.contains(cached))
+                .unwrap_or(0);
 
-    let mut fs = FS::default();
+            if let Some(oldest) = self.chunk_cache_order.remove(oldest) {
+                self.chunk_cache.remove(&oldest);
+            }
+        }
+    }
+
+    fn evict_chunks(&mut self, ids: &[ChunkId]) {
+        for id in ids {
+            if self.chunk_cache.remove(id).is_some() {
+                self.chunk_cache_order.retain(|cached| cached != id);
+            }
+        }
+    }
+
+    // Uploads the chunks of a dirty file that changed, then drops the chunks they replace
+    // Returns how many chunks were written
+    fn flush_data(&mut self, ino: u64) -> Result<usize, c_int> {
+        if !self.dirty.contains(&ino) {
+            return Ok(0);
+        }
+
+        let Some(data) = self.data_table.get(&ino) else {
+            return Err(ENOENT);
+        };
+
+        let old_chunks = self.chunk_table.get(&ino).cloned().unwrap_or_default();
+        let kept = self.kept_chunks(ino, data.len());
+        let mut chunks = old_chunks[..kept].to_vec();
+
+        // Even a changed last chunk goes up under a new id, the saved index
+        // reads the old one until the new index is saved
+        chunks.extend(self.upload_chunks(ino, kept)?);
+
+        let stale: Vec<ChunkId> = old_chunks
+            .into_iter()
+            .filter(|id| !chunks.contains(id))
+            .collect();
+
+        let data = &self.data_table[&ino];
+
+        for (id, chunk) in chunks.iter().zip(data.chunks(self.chunk_size)).skip(kept) {
+            self.checksums.insert(*id, backend::checksum(chunk));
+        }
+
+        for id in &stale {
+            self.checksums.remove(id);
+        }
+
+        let written = chunks.len() - kept;
+        self.chunk_table.insert(ino, chunks);
+        self.chunk_sizes.insert(ino, self.chunk_size);
+        self.dirty.remove(&ino);
+        self.dirty_from.remove(&ino);
+        self.update_dirty_bytes();
+        self.save_index();
+        self.delete_after_save(stale);
+
+        Ok(written)
+    }
+
+    // How many chunks of a dirty file of `len` bytes a flush keeps. Chunks
+    // entirely before the first changed byte are still current, as long as
+    // they were cut at the same size.
+    fn kept_chunks(&self, ino: u64, len: usize) -> usize {
+        let stored = self.chunk_table.get(&ino).map_or(0, Vec::len);
+
+        let kept = match self.chunk_sizes.get(&ino) {
+            Some(&size) if size == self.chunk_size => self
+                .dirty_from
+                .get(&ino)
+                .map_or(0, |&from| from as usize / self.chunk_size),
+            _ => 0,
+        };
+
+        kept.min(stored).min(len.div_ceil(self.chunk_size))
+    }
+
+    // How many chunks the next flush of the file uploads
+    fn pending_chunks(&self, ino: u64) -> usize {
+        match self.data_table.get(&ino) {
+            Some(data) if self.dirty.contains(&ino) => {
+                data.len().div_ceil(self.chunk_size) - self.kept_chunks(ino, data.len())
+            }
+            _ => 0,
+        }
+    }
+
+    // Uploads the chunks from index `skip` on in batches, recording the finished chunks
+    // in the index whenever more remain. A flush that fails partway resumes from what it
+    // already uploaded.
+    fn upload_chunks(&mut self, ino: u64, skip: usize) -> Result<Vec<ChunkId>, c_int> {
+        // Ranges rather than slices, the index is saved between batches
+        let len = self.data_table[&ino].len();
+        let slices: Vec<Range<usize>> = (skip * self.chunk_size..len)
+            .step_by(self.chunk_size)
+            .map(|start| start..(start + self.chunk_size).min(len))
+            .collect();
+        let hash = hash_data(&self.data_table[&ino][(skip * self.chunk_size).min(len)..]);
+
+        let mut upload = match self.uploads.remove(&ino) {
+            Some(upload) if upload.hash == hash && upload.chunks.len() == slices.len() => upload,
+            stale => {
+                if let Some(stale) = stale {
+                    self.delete_chunks(&stale.uploaded());
+                }
+
+                PartialUpload {
+                    hash,
+                    chunks: vec![None; slices.len()],
+                }
+            }
+        };
+
+        let missing: Vec<usize> = (0..slices.len())
+            .filter(|&i| upload.chunks[i].is_none())
+            .collect();
+        let batches: Vec<&[usize]> = missing.chunks(self.concurrency).collect();
+
+        // Chunks left over from an earlier attempt count as done from the start
+        let hook = self.progress.clone();
+        let tracker = hook.as_ref().map(|hook| {
+            let pending: usize = missing.iter().map(|&i| slices[i].len()).sum();
+            let total_bytes = slices.iter().map(|slice| slice.len() as u64).sum::<u64>();
+
+            Tracker::start(
+                hook,
+                Progress {
+                    ino,
+                    chunks: slices.len() - missing.len(),
+                    total_chunks: slices.len(),
+                    bytes: total_bytes - pending as u64,
+                    total_bytes,
+                    started: Instant::now(),
+                },
+            )
+        });
+
+        for (n, batch) in batches.iter().enumerate() {
+            let data = &self.data_table[&ino];
+
+            let results = backend::parallel(batch, self.concurrency, |&i| {
+                let result = self.backend.put_chunk(&data[slices[i].clone()]);
+
+                if let (Ok(_), Some(tracker)) = (&result, &tracker) {
+                    tracker.chunk_done(slices[i].len());
+                }
+
+                result
+            });
+
+            let mut error = None;
+
+            for (&i, result) in batch.iter().zip(results) {
+                match result {
+                    Ok(id) => upload.chunks[i] = Some(id),
+                    Err(e) => error = error.or(Some(e)),
+                }
+            }
+
+            if error.is_none() && n + 1 == batches.len() {
+                break;
+            }
+
+            self.uploads.insert(ino, upload.clone());
+            self.save_index();
+
+            if let Some(e) = error {
+                eprintln!("failed to upload a chunk of inode {}: {}", ino, e);
+                return Err(backend::errno(&e));
+            }
+        }
+
+        self.uploads.remove(&ino);
+
+        Ok(upload.uploaded())
+    }
+
+    fn delete_chunks(&self, chunks: &[ChunkId]) {
+        for id in chunks {
+            if let Err(e) = self.backend.delete_chunk(*id) {
+                eprintln!("failed to delete chunk {}: {}", id, e);
+            }
+        }
+    }
+
+    // Times the operation until the timer drops, with --profile
+    fn time(&self, op: &'static str) -> Option<Timer> {
+        self.profile.as_ref().map(|profile| profile.time(op))
+    }
+
+    fn remember(&mut self, ino: u64) {
+        *self.lookup_counts.entry(ino).or_insert(0) += 1;
+    }
+
+    // Handles are never reused, so a stale fh can't reach someone else's open
+    fn open_handle(&mut self, ino: u64) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(
+            fh,
+            Handle {
+                ino,
+                next_offset: 0,
+                listing: None,
+            },
+        );
+
+        fh
+    }
+
+    fn check_handle_limit(&self) -> Result<(), c_int> {
+        match self.max_open_files {
+            Some(max) if self.handles.len() >= max => Err(EMFILE),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_open(&self, ino: u64) -> bool {
+        self.handles.values().any(|handle| handle.ino == ino)
+    }
+
+    fn allocate_inode(&mut self) -> u64 {
+        if let Some(ino) = self.free_inodes.pop() {
+            return ino;
+        }
+
+        self.last_inode += 1;
+        self.last_inode
+    }
+
+    // Unlinked files keep their data and attributes until the kernel forgets
+    // them, since open handles can still read and write through the inode.
+    // Only then is the space they took given back.
+    fn release_inode(&mut self, ino: u64) {
+        let linked = self.lookup_table.values().any(|attr| attr.ino == ino);
+
+        if self.lookup_counts.contains_key(&ino) || linked {
+            return;
+        }
+
+        if let Some(attr) = self.unlinked.remove(&ino) {
+            match self.trash.get_mut(&ino) {
+                Some((_, trashed)) => *trashed = attr,
+                None => self.total_size -= attr.size,
+            }
+        }
+
+        self.data_table.remove(&ino);
+        self.dirty.remove(&ino);
+        self.dirty_from.remove(&ino);
+        self.update_dirty_bytes();
+        self.path_table.remove(&ino);
+
+        if let Some(upload) = self.uploads.remove(&ino) {
+            self.delete_chunks(&upload.uploaded());
+        }
+
+        if self.trash.contains_key(&ino) {
+            self.save_index();
+            return;
+        }
+
+        self.chunk_sizes.remove(&ino);
+
+        if let Some(chunks) = self.chunk_table.remove(&ino) {
+            // The kernel has forgotten the inode, so its number is safe to reuse
+            self.free_inodes.push(ino);
+
+            for id in &chunks {
+                self.checksums.remove(id);
+            }
+
+            self.evict_chunks(&chunks);
+
+            self.save_index();
+            self.delete_after_save(chunks);
+        }
+    }
+
+    fn get_attr(&self, ino: u64) -> Option<&FileAttr> {
+        if let Some(attr) = self.unlinked.get(&ino) {
+            return Some(attr);
+        }
+
+        self.lookup_table.values().find(|v| v.ino == ino)
+    }
+
+    fn is_append_only(&self, ino: u64) -> bool {
+        self.get_attr(ino)
+            .is_some_and(|attr| attr.flags & FS_APPEND_FL != 0)
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.get_attr(ino)
+            .is_some_and(|attr| attr.kind == FileType::Directory)
+    }
+
+    // Growing a file from `old` to `new` bytes must fit in what's left of the capacity
+    fn check_capacity(&self, old: u64, new: u64) -> Result<(), c_int> {
+        let Some(capacity) = self.capacity else {
+            return Ok(());
+        };
+
+        if self.total_size + new.saturating_sub(old) > capacity {
+            return Err(ENOSPC);
+        }
+
+        Ok(())
+    }
+
+    // What the next flush of the file uploads: everything from the first changed byte on
+    fn dirty_bytes(&self, ino: u64) -> u64 {
+        if !self.dirty.contains(&ino) {
+            return 0;
+        }
+
+        let len = self
+            .data_table
+            .get(&ino)
+            .map_or(0, |data| data.len() as u64);
+        let from = self.dirty_from.get(&ino).copied().unwrap_or(0);
+
+        len.saturating_sub(from)
+    }
+
+    fn total_dirty_bytes(&self) -> u64 {
+        self.dirty.iter().map(|&ino| self.dirty_bytes(ino)).sum()
+    }
+
+    fn update_dirty_bytes(&self) {
+        self.metrics
+            .dirty_bytes
+            .store(self.total_dirty_bytes(), Ordering::Relaxed);
+        self.metrics
+            .dirty_files
+            .store(self.dirty.len() as u64, Ordering::Relaxed);
+    }
+
+    // Even an empty file is refused once there's no room left to grow it
+    fn quota_reached(&self) -> bool {
+        self.capacity
+            .is_some_and(|capacity| self.total_size >= capacity)
+    }
+
+    // Trashed files still hold their chunks, and unlinked ones their data
+    // while open, so they count too
+    fn compute_fs_size(&self) -> u64 {
+        let trashed = self
+            .trash
+            .iter()
+            .filter(|(ino, _)| !self.unlinked.contains_key(ino))
+            .map(|(_, (_, attr))| attr);
+
+        self.lookup_table
+            .values()
+            .chain(self.unlinked.values())
+            .chain(trashed)
+            .filter(|v| v.kind != FileType::Directory)
+            .map(|v| v.size)
+            .sum()
+    }
+
+    fn check_fs_size(&mut self) {
+        // total_size is maintained incrementally, recomputing it is only a drift check
+        debug_assert_eq!(self.total_size, self.compute_fs_size());
+
+        #[cfg(test)]
+        {
+            self.size_updates += 1;
+        }
+    }
+
+    // A directory is as big as its entry table, counted the way tmpfs does,
+    // "." and ".." included. How much the files hold is up to statfs.
+    fn dir_size(&self) -> u64 {
+        (self.lookup_table.len() as u64 + 1) * DIRENT_SIZE
+    }
+
+    // The stored size of a directory is left alone, it's filled in when asked for
+    fn with_dir_size(&self, attr: FileAttr) -> FileAttr {
+        if attr.kind != FileType::Directory {
+            return attr;
+        }
+
+        let size = self.dir_size();
+
+        FileAttr {
+            size,
+            blocks: blocks(size),
+            ..attr
+        }
+    }
+
+    // The do_* methods are request handlers without the fuser Request/Reply
+    // plumbing, so they can also be driven directly
+    fn do_getattr(&self, ino: u64) -> Result<FileAttr, c_int> {
+        match self.get_attr(ino) {
+            Some(attr) => Ok(self.with_dir_size(*attr)),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn do_lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let _timer = self.time("lookup");
+
+        let key = (parent, encode_name(name));
+
+        // Repeated probes for a missing name are answered without searching again
+        if self
+            .negative_lookups
+            .get(&key)
+            .is_some_and(|missed| missed.elapsed() < NEGATIVE_LOOKUP_TTL)
+        {
+            return Err(ENOENT);
+        }
+
+        match self.find_entry(parent, &key.1) {
+            Ok(attr) => {
+                self.remember(attr.ino);
+                Ok(attr)
+            }
+            Err(ENOENT) => {
+                if self.negative_lookups.len() >= NEGATIVE_LOOKUP_LIMIT {
+                    self.negative_lookups
+                        .retain(|_, missed| missed.elapsed() < NEGATIVE_LOOKUP_TTL);
+                }
+
+                self.negative_lookups.insert(key, Instant::now());
+                Err(ENOENT)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn find_entry(&self, parent: u64, name: &str) -> Result<FileAttr, c_int> {
+        let Some(dir) = self.get_attr(parent) else {
+            return Err(ENOENT);
+        };
+
+        if dir.kind != FileType::Directory {
+            return Err(ENOTDIR);
+        }
+
+        // Everything lives in the root, no other directory has children
+        if parent != ROOT_INO {
+            return Err(ENOENT);
+        }
+
+        self.lookup_table.get(name).copied().ok_or(ENOENT)
+    }
+
+    fn do_forget(&mut self, ino: u64, nlookup: u64) {
+        let _timer = self.time("forget");
+
+        let Some(count) = self.lookup_counts.get_mut(&ino) else {
+            return;
+        };
+
+        *count = count.saturating_sub(nlookup);
+
+        if *count == 0 {
+            self.lookup_counts.remove(&ino);
+            self.release_inode(ino);
+        }
+    }
+
+    // Reports the lock standing in the way of `lock`, or `lock` itself as F_UNLCK if there's none
+    fn do_getlk(&self, ino: u64, lock: Lock) -> Lock {
+        self.locks.conflict(ino, &lock).unwrap_or(Lock {
+            typ: F_UNLCK,
+            ..lock
+        })
+    }
+
+    fn do_setlk(&mut self, ino: u64, lock: Lock) -> Result<(), c_int> {
+        self.locks.set(ino, lock)?;
+
+        // Unlocking or converting may free up a range someone is waiting on
+        self.wake_lock_waiters();
+
+        Ok(())
+    }
+
+    fn release_locks(&mut self, ino: u64, owner: u64) {
+        if self.locks.release(ino, owner) {
+            self.wake_lock_waiters();
+        }
+    }
+
+    fn wake_lock_waiters(&mut self) {
+        for (reply, result) in self.locks.wake(&mut self.lock_waiters) {
+            match result {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+        }
+    }
+
+    fn do_access(&self, caller: Caller, ino: u64, mask: c_int) -> Result<(), c_int> {
+        let Some(attr) = self.get_attr(ino) else {
+            return Err(ENOENT);
+        };
+
+        if mask == F_OK {
+            return Ok(());
+        }
+
+        if mask & W_OK != 0 && self.read_only {
+            return Err(EROFS);
+        }
+
+        if !caller.may(attr, mask) {
+            return Err(EACCES);
+        }
+
+        Ok(())
+    }
+
+    fn do_open(&mut self, caller: Caller, ino: u64, flags: i32) -> Result<u64, c_int> {
+        let _timer = self.time("open");
+
+        let Some(attr) = self.get_attr(ino) else {
+            return Err(ENOENT);
+        };
+
+        if attr.kind == FileType::Directory && flags & O_ACCMODE != O_RDONLY {
+            return Err(EISDIR);
+        }
+
+        let mut mask = match flags & O_ACCMODE {
+            O_RDONLY => R_OK,
+            O_WRONLY => W_OK,
+            _ => R_OK | W_OK,
+        };
+
+        if flags & O_TRUNC != 0 {
+            mask |= W_OK;
+        }
+
+        if !caller.may(attr, mask) {
+            return Err(EACCES);
+        }
+
+        self.check_handle_limit()?;
+
+        if flags & O_TRUNC != 0 && attr.kind == FileType::RegularFile {
+            self.truncate(ino)?;
+        }
+
+        Ok(self.open_handle(ino))
+    }
+
+    fn do_read(&mut self, fh: u64, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let _timer = self.time("read");
+
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+
+        // Directories have no data, which would otherwise come out as ENOENT
+        if self.is_dir(ino) {
+            return Err(EISDIR);
+        }
+
+        // A read picking up where the handle's last one ended is taken as sequential
+        let prefetch = match self.handles.get_mut(&fh) {
+            Some(handle) if handle.ino == ino => {
+                let sequential = handle.next_offset == offset as u64;
+                handle.next_offset = (offset as u64).saturating_add(size as u64);
+
+                if sequential {
+                    self.readahead
+                } else {
+                    0
+                }
+            }
+            _ => 0,
+        };
+
+        let now = SystemTime::now();
+
+        // Picked up by the next index save, a read alone isn't worth one
+        if let Some(attr) = attr_mut(
+            &mut self.lookup_table,
+            &self.path_table,
+            &mut self.unlinked,
+            ino,
+        ) {
+            if self.atime.should_update(attr, now) {
+                attr.atime = now;
+            }
+        }
+
+        // Only buffered files are read from memory, unlinked ones have no size to go by
+        let streamable = !self.data_table.contains_key(&ino) && !self.dirty.contains(&ino);
+
+        if let Some(attr) = self.get_attr(ino).filter(|_| streamable) {
+            return self.read_chunks(ino, attr.size, offset as u64, size, prefetch);
+        }
+
+        self.load_data(ino)?;
+
+        let data = &self.data_table[&ino];
+        let start = (offset as usize).min(data.len());
+        let end = start.saturating_add(size as usize).min(data.len());
+
+        Ok(data[start..end].to_vec())
+    }
+
+    // Sorted by name, so two listings of the same entries come out alike
+    fn do_readdir(&self, ino: u64) -> Result<Vec<DirEntry>, c_int> {
+        let _timer = self.time("readdir");
+
+        let Some(dir) = self.get_attr(ino) else {
+            return Err(ENOENT);
+        };
+
+        if dir.kind != FileType::Directory {
+            return Err(ENOTDIR);
+        }
+
+        if ino != ROOT_INO {
+            return Err(ENOENT);
+        }
+
+        let mut entries: Vec<DirEntry> = vec![
+            (1, FileType::Directory, ".".into()),
+            (1, FileType::Directory, "..".into()),
+        ];
+
+        // The root's own attributes live in lookup_table too, it isn't a child of itself
+        let mut children: Vec<DirEntry> = self
+            .lookup_table
+            .iter()
+            .filter(|(_, v)| v.ino != ROOT_INO)
+            .map(|(k, v)| (v.ino, v.kind, decode_name(k)))
+            .collect();
+        children.sort_by(|a, b| a.2.cmp(&b.2));
+        entries.extend(children);
+
+        Ok(entries)
+    }
+
+    // Hands out entries from `offset` on until `add` says the reply is full.
+    // A listing is served from a snapshot taken when it starts at offset 0,
+    // so files created or removed between calls can't shift the cookies the
+    // kernel continues from, and nothing is skipped or listed twice.
+    fn do_readdir_from(
+        &mut self,
+        fh: u64,
+        ino: u64,
+        offset: i64,
+        mut add: impl FnMut(u64, i64, FileType, &OsStr) -> bool,
+    ) -> Result<(), c_int> {
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+
+        let snapshot = self
+            .handles
+            .get(&fh)
+            .filter(|handle| handle.ino == ino && offset > 0)
+            .and_then(|handle| handle.listing.clone());
+
+        let entries = match snapshot {
+            Some(entries) => entries,
+            None => {
+                let entries = Arc::new(self.do_readdir(ino)?);
+
+                if let Some(handle) = self.handles.get_mut(&fh).filter(|h| h.ino == ino) {
+                    handle.listing = Some(entries.clone());
+                }
+
+                entries
+            }
+        };
+
+        for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            // The cookie is where the next call picks up
+            if add(*ino, (i + 1) as i64, *kind, name) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The new file belongs to `caller`, who needs to be allowed to add to the root
+    fn do_create(
+        &mut self,
+        caller: Caller,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<FileAttr, c_int> {
+        let _timer = self.time("create");
+
+        if self.read_only {
+            return Err(EROFS);
+        }
+
+        if !self
+            .get_attr(ROOT_INO)
+            .is_some_and(|root| caller.may(root, W_OK | X_OK))
+        {
+            return Err(EACCES);
+        }
+
+        if self.quota_reached() {
+            return Err(ENOSPC);
+        }
+
+        let name = encode_name(name);
+        let (ino, attr) = self.add_file(&name, &[])?;
+
+        let attr = FileAttr {
+            perm: (mode & !umask & 0o7777) as u16,
+            uid: caller.uid,
+            gid: caller.gid,
+            ..attr
+        };
+
+        self.lookup_table.insert(name, attr);
+        self.remember(ino);
+        self.save_index();
+
+        Ok(attr)
+    }
+
+    fn do_unlink(&mut self, name: &OsStr) -> Result<(), c_int> {
+        let _timer = self.time("unlink");
+
+        if self.read_only {
+            return Err(EROFS);
+        }
+
+        let name = encode_name(name);
+
+        let Some(ino) = self.lookup_table.get(&name).map(|attr| attr.ino) else {
+            return Err(ENOENT);
+        };
+
+        // The trash keeps chunks, not buffered writes, so those have to be sent first
+        if self.use_trash {
+            self.flush_data(ino)?;
+        }
+
+        let attr = self.lookup_table.remove(&name).unwrap();
+
+        if self.use_trash {
+            self.trash.insert(attr.ino, (name, attr));
+        }
+
+        self.unlinked.insert(attr.ino, attr);
+
+        self.check_fs_size();
+        self.save_index();
+        self.release_inode(attr.ino);
+
+        Ok(())
+    }
+
+    fn do_setattr(&mut self, ino: u64, changes: AttrChanges) -> Result<FileAttr, c_int> {
+        let _timer = self.time("setattr");
+
+        let AttrChanges {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+        } = changes;
+
+        let metadata_changed =
+            mode.is_some() || uid.is_some() || gid.is_some() || atime.is_some() || mtime.is_some();
+
+        if self.read_only && (size.is_some() || metadata_changed) {
+            return Err(EROFS);
+        }
+
+        if self.is_append_only(ino)
+            && size.is_some_and(|size| self.get_attr(ino).is_some_and(|attr| size < attr.size))
+        {
+            return Err(EPERM);
+        }
+
+        if size.is_some() {
+            self.load_data(ino)?;
+        }
+
+        let Some(attr) = attr_mut(
+            &mut self.lookup_table,
+            &self.path_table,
+            &mut self.unlinked,
+            ino,
+        ) else {
+            return Err(ENOENT);
+        };
+
+        if let Some(size) = size {
+            if size > self.max_file_size {
+                return Err(EFBIG);
+            }
+
+            if self
+                .capacity
+                .is_some_and(|c| self.total_size - attr.size + size > c)
+            {
+                return Err(ENOSPC);
+            }
+
+            let Some(data) = self.data_table.get_mut(&ino) else {
+                return Err(ENOENT);
+            };
+
+            self.total_size = self.total_size - attr.size + size;
+            let from = size.min(data.len() as u64);
+            data.resize(size as usize, 0);
+            self.dirty.insert(ino);
+            mark_dirty_from(&mut self.dirty_from, ino, from);
+
+            attr.size = size;
+            attr.blocks = blocks(size);
+
+            let now = SystemTime::now();
+            attr.mtime = now;
+            attr.ctime = now;
+        }
+
+        if let Some(mode) = mode {
+            attr.perm = (mode & 0o7777) as u16;
+        }
+
+        if let Some(uid) = uid {
+            attr.uid = uid;
+        }
+
+        if let Some(gid) = gid {
+            attr.gid = gid;
+        }
+
+        if let Some(atime) = atime {
+            attr.atime = resolve_time(atime);
+        }
+
+        if let Some(mtime) = mtime {
+            attr.mtime = resolve_time(mtime);
+        }
+
+        if metadata_changed {
+            attr.ctime = SystemTime::now();
+        }
+
+        let attr = *attr;
+        self.update_dirty_bytes();
+        self.check_fs_size();
+
+        // Size changes reach the index with the next flush, nothing else would
+        if metadata_changed {
+            self.save_index();
+        }
+
+        Ok(self.with_dir_size(attr))
+    }
+
+    // Empties the file for O_TRUNC. Nothing is fetched, the old chunks are
+    // dropped by the next flush.
+    fn truncate(&mut self, ino: u64) -> Result<(), c_int> {
+        if self.read_only {
+            return Err(EROFS);
+        }
+
+        if self.is_append_only(ino) {
+            return Err(EPERM);
+        }
+
+        let Some(attr) = attr_mut(
+            &mut self.lookup_table,
+            &self.path_table,
+            &mut self.unlinked,
+            ino,
+        ) else {
+            return Err(ENOENT);
+        };
+
+        if attr.size == 0 {
+            return Ok(());
+        }
+
+        self.total_size -= attr.size;
+        attr.size = 0;
+        attr.blocks = 0;
+
+        let now = SystemTime::now();
+        attr.mtime = now;
+        attr.ctime = now;
+
+        self.data_table.insert(ino, Vec::new());
+        self.dirty.insert(ino);
+        mark_dirty_from(&mut self.dirty_from, ino, 0);
+        self.update_dirty_bytes();
+        self.check_fs_size();
+
+        Ok(())
+    }
+
+    // Only the append-only flag is supported, asking for any other is refused
+    fn do_set_flags(&mut self, ino: u64, flags: u32) -> Result<(), c_int> {
+        if self.read_only {
+            return Err(EROFS);
+        }
+
+        if flags & !FS_APPEND_FL != 0 {
+            return Err(EOPNOTSUPP);
+        }
+
+        let Some(attr) = attr_mut(
+            &mut self.lookup_table,
+            &self.path_table,
+            &mut self.unlinked,
+            ino,
+        ) else {
+            return Err(ENOENT);
+        };
+
+        if attr.flags != flags {
+            attr.flags = flags;
+            attr.ctime = SystemTime::now();
+            self.save_index();
+        }
+
+        Ok(())
+    }
+
+    // A write is applied whole, growing the file and copying in the data with
+    // nothing else in between, as SharedFS runs each request under its lock.
+    // Where writes overlap, the one that got the lock last wins.
+    fn do_write(&mut self, ino: u64, offset: i64, data: &[u8]) -> Result<u32, c_int> {
+        let _timer = self.time("write");
+
+        if self.read_only {
+            return Err(EROFS);
+        }
+
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+
+        let offset = offset as u64;
+
+        let Some(end) = offset.checked_add(data.len() as u64) else {
+            return Err(EFBIG);
+        };
+
+        if end > self.max_file_size {
+            return Err(EFBIG);
+        }
+
+        let Some(attr) = self.get_attr(ino) else {
+            return Err(ENOENT);
+        };
+
+        if attr.kind == FileType::Directory {
+            return Err(EISDIR);
+        }
+
+        if attr.flags & FS_APPEND_FL != 0 && offset != attr.size {
+            return Err(EPERM);
+        }
+
+        self.check_capacity(attr.size, end)?;
+        self.load_data(ino)?;
+
+        let Some(attrs) = attr_mut(
+            &mut self.lookup_table,
+            &self.path_table,
+            &mut self.unlinked,
+            ino,
+        ) else {
+            return Err(ENOENT);
+        };
+
+        let Some(existing_data) = self.data_table.get_mut(&ino) else {
+            return Err(ENOENT);
+        };
+
+        let from = offset.min(existing_data.len() as u64);
+
+        // Growing the file also zero-fills any hole before offset
+        if existing_data.len() < end as usize {
+            existing_data.resize(end as usize, 0);
+        }
+
+        existing_data[offset as usize..end as usize].copy_from_slice(data);
+
+        if end > attrs.size {
+            self.total_size += end - attrs.size;
+            attrs.size = end;
+            attrs.blocks = blocks(end);
+        }
+
+        let now = SystemTime::now();
+        attrs.mtime = now;
+        attrs.ctime = now;
+
+        self.dirty.insert(ino);
+        mark_dirty_from(&mut self.dirty_from, ino, from);
+        self.update_dirty_bytes();
+        self.check_fs_size();
+        self.limit_dirty_bytes();
+
+        Ok(data.len() as u32)
+    }
+
+    fn do_fallocate(&mut self, ino: u64, offset: i64, length: i64, mode: i32) -> Result<(), c_int> {
+        let _timer = self.time("fallocate");
+
+        if self.read_only {
+            return Err(EROFS);
+        }
+
+        // Only plain preallocation, punching holes and the like isn't supported
+        if mode != 0 {
+            return Err(EOPNOTSUPP);
+        }
+
+        if offset < 0 || length <= 0 {
+            return Err(EINVAL);
+        }
+
+        let Some(end) = (offset as u64).checked_add(length as u64) else {
+            return Err(EFBIG);
+        };
+
+        if end > self.max_file_size {
+            return Err(EFBIG);
+        }
+
+        let Some(attr) = self.get_attr(ino) else {
+            return Err(ENOENT);
+        };
+
+        self.check_capacity(attr.size, end)?;
+        self.load_data(ino)?;
+
+        let Some(attr) = attr_mut(
+            &mut self.lookup_table,
+            &self.path_table,
+            &mut self.unlinked,
+            ino,
+        ) else {
+            return Err(ENOENT);
+        };
+
+        let Some(data) = self.data_table.get_mut(&ino) else {
+            return Err(ENOENT);
+        };
+
+        if end > attr.size {
+            mark_dirty_from(&mut self.dirty_from, ino, data.len() as u64);
+            data.resize(end as usize, 0);
+            self.total_size += end - attr.size;
+            self.dirty.insert(ino);
+
+            attr.size = end;
+            attr.blocks = blocks(end);
+
+            let now = SystemTime::now();
+            attr.mtime = now;
+            attr.ctime = now;
+        }
+
+        self.update_dirty_bytes();
+        self.check_fs_size();
+
+        Ok(())
+    }
+
+    fn do_flush(&mut self, ino: u64) -> Result<(), c_int> {
+        let _timer = self.time("flush");
+
+        if !self.chunk_table.contains_key(&ino) {
+            return Err(ENOENT);
+        }
+
+        self.flush_data(ino)?;
+
+        Ok(())
+    }
+
+    fn do_release(&mut self, ino: u64, fh: u64) -> Result<(), c_int> {
+        let _timer = self.time("release");
+
+        self.handles.remove(&fh);
+
+        if !self.chunk_table.contains_key(&ino) {
+            return Err(ENOENT);
+        }
+
+        self.flush_data(ino)?;
+
+        // Clean data can always be fetched again, no need to keep it once the last handle closes
+        if !self.is_open(ino) {
+            self.data_table.remove(&ino);
+        }
+
+        Ok(())
+    }
+
+    // Past --max-dirty, the files with the most unflushed data are uploaded
+    // until the rest fits, rather than buffering without bound until close
+    fn limit_dirty_bytes(&mut self) {
+        let Some(limit) = self.max_dirty else {
+            return;
+        };
+
+        while self.total_dirty_bytes() > limit {
+            let Some(ino) = self
+                .dirty
+                .iter()
+                .copied()
+                .max_by_key(|&ino| self.dirty_bytes(ino))
+            else {
+                return;
+            };
+
+            self.metrics
+                .backpressure_flushes
+                .fetch_add(1, Ordering::Relaxed);
+
+            // The write itself went through, its data stays buffered for the next flush
+            if let Err(e) = self.flush_data(ino) {
+                eprintln!("failed to flush inode {} over the dirty limit: {}", ino, e);
+                return;
+            }
+        }
+    }
+}
+
+impl<B: StorageBackend> Filesystem for FS<B> {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        // Otherwise the kernel handles locks itself and never calls getlk/setlk
+        if let Err(unsupported) = config.add_capabilities(FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS) {
+            eprintln!(
+                "kernel doesn't support lock capabilities {:#x}",
+                unsupported
+            );
+        }
+
+        // Otherwise the kernel truncates with a separate setattr, fetching the whole file first
+        if let Err(unsupported) = config.add_capabilities(FUSE_ATOMIC_O_TRUNC) {
+            eprintln!("kernel doesn't support atomic O_TRUNC {:#x}", unsupported);
+        }
+
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        let dirty: Vec<u64> = self.dirty.iter().copied().collect();
+
+        for ino in dirty {
+            // Failures are already logged, keep flushing the rest
+            let _ = self.flush_data(ino);
+        }
+
+        self.write_index();
+
+        if let Some(profile) = &self.profile {
+            eprint!("profile:\n{}", profile.summary());
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.maybe_refresh();
+
+        match self.do_lookup(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.do_forget(ino, nlookup);
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let _timer = self.time("getattr");
+        self.maybe_refresh();
+
+        match self.do_getattr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.do_read(fh, ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        // Not halfway through a listing, the kernel continues from our snapshot
+        if offset == 0 {
+            self.maybe_refresh();
+        }
+
+        let listed = self.do_readdir_from(fh, ino, offset, |ino, cookie, kind, name| {
+            reply.add(ino, cookie, kind, name)
+        });
+
+        match listed {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        _parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.do_create(Caller::of(req), name, mode, umask) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        _parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        // Checked up front, the file shouldn't be created without a handle to return
+        if let Err(e) = self.check_handle_limit() {
+            reply.error(e);
+            return;
+        }
+
+        match self.do_create(Caller::of(req), name, mode, umask) {
+            Ok(attr) => {
+                let fh = self.open_handle(attr.ino);
+                reply.created(&TTL, &attr, 0, fh, 0);
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, _parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        match self.do_unlink(name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        match self.do_open(Caller::of(req), ino, flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        match self.do_access(Caller::of(req), ino, mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+        let Some(attr) = self.get_attr(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+
+        if attr.kind != FileType::Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+
+        if let Err(e) = self.check_handle_limit() {
+            reply.error(e);
+            return;
+        }
+
+        let fh = self.open_handle(ino);
+        reply.opened(fh, 0);
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        match self.do_write(ino, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        // POSIX locks go away when their owner closes any descriptor of the file
+        self.release_locks(ino, lock_owner);
+
+        match self.do_flush(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        // Set for flock() locks, which last until the last descriptor is closed
+        if let Some(owner) = lock_owner {
+            self.release_locks(ino, owner);
+        }
+
+        match self.do_release(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let changes = AttrChanges {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+        };
+
+        match self.do_setattr(ino, changes) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+
+        let found = self.do_getlk(ino, lock);
+        reply.locked(found.start, found.end, found.typ, found.pid);
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+
+        match self.do_setlk(ino, lock) {
+            Ok(()) => reply.ok(),
+            // Blocking requests are answered once the range frees up
+            Err(EAGAIN) if sleep => self.lock_waiters.push((ino, lock, reply)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        match self.do_fallocate(ino, offset, length, mode) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // Files are chunks in a remote store, there's no block device to map into
+    fn bmap(&mut self, _req: &Request<'_>, ino: u64, _blocksize: u32, idx: u64, reply: ReplyBmap) {
+        eprintln!(
+            "bmap of block {} of inode {} refused, there are no blocks",
+            idx, ino
+        );
+        reply.error(EINVAL);
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let used = self.total_size.div_ceil(512);
+
+        // Without a capacity there is nothing to measure free space against
+        let blocks = self.capacity.map_or(used, |capacity| capacity / 512);
+        let free = blocks.saturating_sub(used);
+
+        reply.statfs(
+            blocks,
+            free,
+            free,
+            self.lookup_table.len() as u64,
+            0,
+            512,
+            255,
+            512,
+        );
+    }
+
+    fn ioctl(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        _out_size: u32,
+        reply: fuser::ReplyIoctl,
+    ) {
+        match cmd {
+            // The flags are an int, whatever size the ioctl number says
+            FS_IOC_GETFLAGS => match self.get_attr(ino) {
+                Some(attr) => reply.ioctl(0, &attr.flags.to_ne_bytes()),
+                None => reply.error(ENOENT),
+            },
+            FS_IOC_SETFLAGS => {
+                let Some(flags) = in_data.get(..4) else {
+                    reply.error(EINVAL);
+                    return;
+                };
+
+                let flags = u32::from_ne_bytes(flags.try_into().unwrap());
+
+                match self.do_set_flags(ino, flags) {
+                    Ok(()) => reply.ioctl(0, &[]),
+                    Err(e) => reply.error(e),
+                }
+            }
+            // The ioctl's return value is the number of chunks written
+            IOCTL_FLUSH => match self.flush_data(ino) {
+                Ok(written) => reply.ioctl(written as i32, &[]),
+                Err(e) => reply.error(e),
+            },
+            IOCTL_PENDING_CHUNKS => {
+                let pending = self.pending_chunks(ino) as u64;
+                reply.ioctl(0, &pending.to_ne_bytes());
+            }
+            _ => reply.error(ENOTTY),
+        }
+    }
+}
+
+// 512-byte blocks, as st_blocks counts them
+fn blocks(size: u64) -> u64 {
+    size.div_ceil(512)
+}
+
+fn root_attr(perm: u16) -> FileAttr {
+    let owner = Caller::mounter();
+    let now = SystemTime::now();
+
+    FileAttr {
+        ino: ROOT_INO,
+        size: 0,
+        blocks: 0,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind: FileType::Directory,
+        perm,
+        nlink: 2,
+        uid: owner.uid,
+        gid: owner.gid,
+        rdev: 0,
+        flags: 0,
+        blksize: 512,
+    }
+}
+
+// Octal, with or without a leading 0, e.g. 700 or 0755
+fn parse_mode(value: &str) -> Result<u16, String> {
+    u16::from_str_radix(value.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid mode `{}`, expected octal like 0755", value))
+}
+
+// A byte count with an optional binary suffix, like 512K or 8M
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+
+    let multiplier: u64 = match value[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        suffix => return Err(format!("unknown size suffix `{}`", suffix)),
+    };
+
+    digits
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size `{}`: {}", value, e))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size `{}` is too large", value))
+}
+
+// A comma-separated list as given to -o, like ro,default_permissions
+fn parse_mount_options(value: &str) -> Result<Vec<MountOption>, String> {
+    value
+        .split(',')
+        .filter(|option| !option.is_empty())
+        .map(|option| {
+            let option = match option.split_once('=') {
+                Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+                Some(("subtype", name)) => MountOption::Subtype(name.to_string()),
+                Some(_) => return Err(format!("unknown mount option `{}`", option)),
+                None => match option {
+                    "ro" => MountOption::RO,
+                    "rw" => MountOption::RW,
+                    "allow_other" => MountOption::AllowOther,
+                    "allow_root" => MountOption::AllowRoot,
+                    "auto_unmount" => MountOption::AutoUnmount,
+                    "default_permissions" => MountOption::DefaultPermissions,
+                    "dev" => MountOption::Dev,
+                    "nodev" => MountOption::NoDev,
+                    "suid" => MountOption::Suid,
+                    "nosuid" => MountOption::NoSuid,
+                    "exec" => MountOption::Exec,
+                    "noexec" => MountOption::NoExec,
+                    "atime" => MountOption::Atime,
+                    "noatime" => MountOption::NoAtime,
+                    "dirsync" => MountOption::DirSync,
+                    "sync" => MountOption::Sync,
+                    "async" => MountOption::Async,
+                    _ => return Err(format!("unknown mount option `{}`", option)),
+                },
+            };
+
+            Ok(option)
+        })
+        .collect()
+}
+
+// The defaults with the -o options on top. The first option is always RO or
+// RW, the extra read-only mounts swap it out.
+fn mount_options(read_only: bool, extra: Vec<MountOption>) -> Vec<MountOption> {
+    let mut options = vec![
+        if read_only {
+            MountOption::RO
+        } else {
+            MountOption::RW
+        },
+        MountOption::FSName("discordfs".to_string()),
+        MountOption::AutoUnmount,
+        MountOption::AllowOther,
+    ];
+
+    for option in extra {
+        match option {
+            MountOption::RO | MountOption::RW => continue,
+            MountOption::FSName(_) => options.retain(|o| !matches!(o, MountOption::FSName(_))),
+            // fusermount refuses it alongside allow_other
+            MountOption::AllowRoot => options.retain(|o| *o != MountOption::AllowOther),
+            _ => {}
+        }
+
+        if !options.contains(&option) {
+            options.push(option);
+        }
+    }
+
+    options
+}
+
+// Where the attributes of `ino` are kept, unlinked files that are still in
+// use being gone from the lookup table
+fn attr_mut<'a>(
+    lookup_table: &'a mut HashMap<String, FileAttr>,
+    path_table: &HashMap<u64, String>,
+    unlinked: &'a mut HashMap<u64, FileAttr>,
+    ino: u64,
+) -> Option<&'a mut FileAttr> {
+    if let Some(attr) = unlinked.get_mut(&ino) {
+        return Some(attr);
+    }
+
+    lookup_table.get_mut(path_table.get(&ino)?)
+}
+
+// Every --store given, with chunks spread across them when there are several
+fn open_stores(matches: &ArgMatches) -> ShardBackend<DirBackend> {
+    let stores: Vec<DirBackend> = matches
+        .get_many::<PathBuf>("store")
+        .unwrap()
+        .map(|store| DirBackend::open(store.clone()).unwrap())
+        .collect();
+
+    if stores.len() > shard::MAX_SHARDS {
+        eprintln!("error: at most {} stores can be given", shard::MAX_SHARDS);
+        process::exit(2);
+    }
+
+    ShardBackend::new(stores)
+}
+
+// Remembers the lowest offset changed since the last flush
+fn mark_dirty_from(dirty_from: &mut HashMap<u64, u64>, ino: u64, from: u64) {
+    let from = dirty_from.get(&ino).map_or(from, |&old| old.min(from));
+    dirty_from.insert(ino, from);
+}
+
+fn resolve_time(time: TimeOrNow) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+fn hash_data(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+type DirEntry = (u64, FileType, OsString);
+
+// Per-open state, keyed by the fh handed to the kernel
+struct Handle {
+    ino: u64,
+    next_offset: u64,
+    // A directory's entries as of the start of the current listing
+    listing: Option<Arc<Vec<DirEntry>>>,
+}
+
+// Who a request comes from. FUSE doesn't pass on supplementary groups, so
+// only the primary one counts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Caller {
+    uid: u32,
+    gid: u32,
+}
+
+impl Caller {
+    fn of(req: &Request<'_>) -> Self {
+        Caller {
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+
+    // Whoever mounted the filesystem owns the root and the files it starts with
+    fn mounter() -> Self {
+        Caller {
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    // The rules the kernel applies with default_permissions, so a mount with
+    // and without it agree. `mask` is made of R_OK, W_OK and X_OK.
+    fn may(&self, attr: &FileAttr, mask: c_int) -> bool {
+        let perm = attr.perm as c_int;
+
+        // root can do anything but run a file nobody can execute
+        if self.uid == 0 {
+            return mask & X_OK == 0 || attr.kind == FileType::Directory || perm & 0o111 != 0;
+        }
+
+        let granted = if self.uid == attr.uid {
+            perm >> 6
+        } else if self.gid == attr.gid {
+            perm >> 3
+        } else {
+            perm
+        };
+
+        granted & mask == mask
+    }
+}
+
+// What a setattr asks to change, the fields left at None stay as they are
+#[derive(Default)]
+struct AttrChanges {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<TimeOrNow>,
+    mtime: Option<TimeOrNow>,
+}
+
+#[derive(Clone, Copy)]
+enum AtimePolicy {
+    Relatime,
+    Noatime,
+    Strict,
+}
+
+impl AtimePolicy {
+    fn parse(policy: &str) -> Self {
+        match policy {
+            "noatime" => AtimePolicy::Noatime,
+            "strict" => AtimePolicy::Strict,
+            _ => AtimePolicy::Relatime,
+        }
+    }
+
+    // Relatime only updates once the file changed since the last access, or
+    // the last access is more than a day old
+    fn should_update(self, attr: &FileAttr, now: SystemTime) -> bool {
+        match self {
+            AtimePolicy::Noatime => false,
+            AtimePolicy::Strict => true,
+            AtimePolicy::Relatime => {
+                attr.atime <= attr.mtime
+                    || attr.atime <= attr.ctime
+                    || now
+                        .duration_since(attr.atime)
+                        .is_ok_and(|age| age > Duration::from_secs(24 * 60 * 60))
+            }
+        }
+    }
+}
+
+// Blocks SIGINT/SIGTERM in this thread and every thread spawned from it, so
+// only the shutdown handler ever takes them. Has to run before the first
+// thread is spawned, any thread that leaves them unblocked gets killed by them.
+fn block_shutdown_signals() -> libc::sigset_t {
+    let mut signals: libc::sigset_t = unsafe { mem::zeroed() };
+
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+    }
+
+    signals
+}
+
+// Unmounts on the blocked signals so the session loop returns and `destroy` gets
+// a chance to persist the index instead of the process dying mid-flight.
+fn spawn_shutdown_handler(
+    signals: libc::sigset_t,
+    on_signal: impl FnOnce() + Send + 'static,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut signal = 0;
+        unsafe { libc::sigwait(&signals, &mut signal) };
+
+        eprintln!("received signal {}, unmounting", signal);
+
+        on_signal();
+    })
+}
+
+fn main() {
+    let matches = command!()
+        .args_conflicts_with_subcommands(true)
+        .arg(
+            Arg::new("foreground")
+                .long("foreground")
+                .help("Stay attached to the terminal, which is the default")
+                .conflicts_with("daemon")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("daemon")
+                .long("daemon")
+                .help("Go into the background once the filesystem is mounted")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("pid-file")
+                .long("pid-file")
+                .value_name("PATH")
+                .help("Write the process id here while mounted")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("PATH")
+                .help("Where --daemon appends its output, which is discarded otherwise")
+                .requires("daemon")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .help("Mount the filesystem read-only")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("options")
+                .short('o')
+                .value_name("OPTIONS")
+                .help("Extra mount options, comma-separated, e.g. default_permissions,noexec")
+                .value_parser(parse_mount_options)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("atime")
+                .long("atime")
+                .value_name("POLICY")
+                .help("When reads update the access time")
+                .value_parser(["relatime", "noatime", "strict"])
+                .default_value("relatime"),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .value_name("MODE")
+                .help("Whether whole fetched chunks are checked against their checksums")
+                .value_parser(["on", "off"])
+                .default_value("on"),
+        )
+        .arg(
+            Arg::new("progress")
+                .long("progress")
+                .help("Show a progress bar while files upload, when stdout is a terminal")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("root-mode")
+                .long("root-mode")
+                .value_name("MODE")
+                .help("Permissions of the mount's root directory, in octal [default: 0755]")
+                .value_parser(parse_mode),
+        )
+        .arg(
+            Arg::new("trash")
+                .long("trash")
+                .help("Keep the chunks of deleted files until `empty-trash` is run")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .help("Keep everything in memory and only log what would be uploaded or deleted")
+                .conflicts_with("store")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("store")
+                .long("store")
+                .value_name("DIR")
+                .help(
+                    "Keep chunks and the index in this directory instead of in memory. \
+                     Given several times, chunks are spread across them in turn and the \
+                     first keeps the index, they have to be given in the same order every time",
+                )
+                .value_parser(value_parser!(PathBuf))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("mirror")
+                .long("mirror")
+                .value_name("DIR")
+                .help("Also keep a copy of every chunk in this directory and read from it first")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("cache-dir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .help("Keep recently read chunks in this directory across restarts")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("cache-size")
+                .long("cache-size")
+                .value_name("SIZE")
+                .help("How much --cache-dir may hold before the least recently read chunks go")
+                .value_parser(parse_size)
+                .default_value("1G"),
+        )
+        .arg(
+            Arg::new("index")
+                .long("index")
+                .value_name("PATH")
+                .help("Persist the metadata index to this file and load it on startup")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("index-format")
+                .long("index-format")
+                .value_name("FORMAT")
+                .help("How to write the index, binary is smaller and quicker for large trees")
+                .long_help(
+                    "How to write the index, binary is smaller and quicker for large trees. \
+                     Either format is read. Without this, the index is written in the format \
+                     it was found in, or as json for a new one.",
+                )
+                .value_parser(["json", "binary"]),
+        )
+        .arg(
+            Arg::new("checkpoint-interval")
+                .long("checkpoint-interval")
+                .value_name("SECONDS")
+                .help("Save index changes at most this often instead of right away, and on unmount")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("refresh")
+                .long("refresh")
+                .visible_alias("refresh-interval")
+                .value_name("SECONDS")
+                .help("Pick up changes other mounts saved to the index at most this often")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("DIR")
+                .help("Fill a filesystem without an index with the files in this directory")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("also-mount")
+                .long("also-mount")
+                .value_name("DIR")
+                .help("Mount the same filesystem here as well, can be repeated")
+                .value_parser(value_parser!(PathBuf))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("also-mount-ro")
+                .long("also-mount-ro")
+                .value_name("DIR")
+                .help("Mount the same filesystem here as well, read-only")
+                .value_parser(value_parser!(PathBuf))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("max-file-size")
+                .long("max-file-size")
+                .value_name("SIZE")
+                .help("Largest size a single file may grow to")
+                .value_parser(parse_size)
+                .default_value("1G"),
+        )
+        .arg(
+            Arg::new("max-dirty")
+                .long("max-dirty")
+                .value_name("SIZE")
+                .help("Flush files early once this much written data is waiting to be uploaded")
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("chunk-size")
+                .long("chunk-size")
+                .value_name("SIZE")
+                .help("Size to split files into when uploading them, e.g. 8M")
+                .value_parser(parse_size)
+                .default_value("8M"),
+        )
+        .arg(
+            Arg::new("attachment-limit")
+                .long("attachment-limit")
+                .value_name("SIZE")
+                .help("Largest attachment the channel accepts, chunks can't be bigger")
+                .long_help(
+                    "Largest attachment the channel accepts, chunks can't be bigger. \
+                     Discord raises it with the server's boost tier: 8M without boosts, \
+                     50M at level 2 and 100M at level 3.",
+                )
+                .value_parser(parse_size)
+                .default_value("8M"),
+        )
+        .arg(
+            Arg::new("channel-capacity")
+                .long("channel-capacity")
+                .visible_alias("quota")
+                .value_name("SIZE")
+                .help("Total number of bytes the filesystem may hold")
+                .value_parser(parse_size),
+        )
+        .arg(
+            Arg::new("max-upload-rate")
+                .long("max-upload-rate")
+                .visible_alias("upload-rate")
+                .value_name("BYTES")
+                .help("Limit uploads to this many bytes per second")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("max-download-rate")
+                .long("max-download-rate")
+                .visible_alias("download-rate")
+                .value_name("BYTES")
+                .help("Limit downloads to this many bytes per second")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("metrics-addr")
+                .long("metrics-addr")
+                .value_name("ADDR")
+                .help("Serve Prometheus metrics over HTTP on this address")
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("verbose-stats")
+                .long("verbose-stats")
+                .help("Log a summary of cache, transfer and dirty data stats periodically")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stats-interval")
+                .long("stats-interval")
+                .value_name("SECONDS")
+                .help("How often --verbose-stats logs")
+                .value_parser(value_parser!(u64).range(1..))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .help("Time every operation and backend call, and log a summary on unmount")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max-open-files")
+                .long("max-open-files")
+                .value_name("COUNT")
+                .help("Fail opens with EMFILE once this many files and directories are open")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("concurrency")
+                .long("concurrency")
+                .value_name("COUNT")
+                .help("How many chunks to transfer at once")
+                .value_parser(value_parser!(u64).range(1..))
+                .default_value("4"),
+        )
+        .arg(
+            Arg::new("readahead")
+                .long("readahead")
+                .value_name("CHUNKS")
+                .help("How many chunks to fetch ahead of sequential reads, 0 to disable")
+                .value_parser(value_parser!(u64))
+                .default_value("2"),
+        )
+        .arg(
+            Arg::new("retries")
+                .long("retries")
+                .value_name("COUNT")
+                .help("How often to retry a backend call that failed with a transient error")
+                .value_parser(value_parser!(u32))
+                .default_value("3"),
+        )
+        .subcommand(
+            Command::new("fsck")
+                .about("Check the index against the stored chunks without mounting")
+                .arg(
+                    Arg::new("store")
+                        .long("store")
+                        .value_name("DIR")
+                        .help("The store, or every store in the order they're mounted with")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("index")
+                        .long("index")
+                        .value_name("PATH")
+                        .help("Check this index file instead of the one in the store")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("prune")
+                        .long("prune")
+                        .help("Remove orphaned chunks and dangling path entries")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("verify")
+                        .long("verify")
+                        .help("Also compare chunk contents against their recorded checksums")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("Delete stored chunks that no file references")
+                .arg(
+                    Arg::new("store")
+                        .long("store")
+                        .value_name("DIR")
+                        .help("The store, or every store in the order they're mounted with")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("index")
+                        .long("index")
+                        .value_name("PATH")
+                        .help("Use this index file instead of the one in the store")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("grace")
+                        .long("grace")
+                        .value_name("SECONDS")
+                        .help("Leave chunks younger than this alone, they may still be in flight")
+                        .value_parser(value_parser!(u64))
+                        .default_value("3600"),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Time writing and reading files against the store")
+                .arg(
+                    Arg::new("store")
+                        .long("store")
+                        .value_name("DIR")
+                        .help("The store, or every store in the order they're mounted with")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .value_name("SIZE")
+                        .help("Size of the files to time, can be given more than once")
+                        .value_parser(parse_size)
+                        .action(ArgAction::Append)
+                        .default_values(["64K", "8M"]),
+                )
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .value_name("COUNT")
+                        .help("How many files of each size to write and read")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("chunk-size")
+                        .long("chunk-size")
+                        .value_name("SIZE")
+                        .help("Size to split files into, as when mounted")
+                        .value_parser(parse_size)
+                        .default_value("8M"),
+                )
+                .arg(
+                    Arg::new("concurrency")
+                        .long("concurrency")
+                        .value_name("COUNT")
+                        .help("How many chunks to transfer at once")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("4"),
+                ),
+        )
+        .subcommand(
+            Command::new("messages")
+                .about("List the stored chunks and what they back")
+                .arg(
+                    Arg::new("store")
+                        .long("store")
+                        .value_name("DIR")
+                        .help("The store, or every store in the order they're mounted with")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("index")
+                        .long("index")
+                        .value_name("PATH")
+                        .help("Use this index file instead of the one in the store")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("list")
+                        .long("list")
+                        .help("Only list them, which is also the default")
+                        .conflicts_with("prune")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("prune")
+                        .long("prune")
+                        .help("Delete the chunks the index doesn't reference after listing them")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("grace")
+                        .long("grace")
+                        .value_name("SECONDS")
+                        .help("Leave chunks younger than this alone when pruning")
+                        .requires("prune")
+                        .value_parser(value_parser!(u64))
+                        .default_value("3600"),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Write every file with its metadata and contents to a JSON lines file")
+                .arg(
+                    Arg::new("store")
+                        .long("store")
+                        .value_name("DIR")
+                        .help("The store, or every store in the order they're mounted with")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("index")
+                        .long("index")
+                        .value_name("PATH")
+                        .help("Use this index file instead of the one in the store")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .value_name("PATH")
+                        .help("Where to write the export")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Add the files from an export to the store")
+                .arg(
+                    Arg::new("store")
+                        .long("store")
+                        .value_name("DIR")
+                        .help("The store, or every store in the order they're mounted with")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("index")
+                        .long("index")
+                        .value_name("PATH")
+                        .help("Use this index file instead of the one in the store")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("in")
+                        .long("in")
+                        .value_name("PATH")
+                        .help("The export to read")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("empty-trash")
+                .about("Delete the chunks of files removed while mounted with --trash")
+                .arg(
+                    Arg::new("store")
+                        .long("store")
+                        .value_name("DIR")
+                        .help("The store, or every store in the order they're mounted with")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("index")
+                        .long("index")
+                        .value_name("PATH")
+                        .help("Use this index file instead of the one in the store")
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .get_matches();
+
+    if let Some(("fsck", matches)) = matches.subcommand() {
+        let backend = SparseBackend::new(open_stores(matches));
+        let index_path = matches.get_one::<PathBuf>("index");
+
+        fsck::run(
+            &backend,
+            index_path.map(|p| p.as_path()),
+            matches.get_flag("prune"),
+            matches.get_flag("verify"),
+        );
+
+        return;
+    }
+
+    if let Some(("messages", matches)) = matches.subcommand() {
+        let backend = SparseBackend::new(open_stores(matches));
+        let index_path = matches.get_one::<PathBuf>("index");
+
+        let grace = Duration::from_secs(*matches.get_one::<u64>("grace").unwrap());
+
+        messages::run(
+            &backend,
+            index_path.map(|p| p.as_path()),
+            matches.get_flag("prune").then_some(grace),
+        );
+
+        return;
+    }
+
+    if let Some(("export", matches)) = matches.subcommand() {
+        let backend = SparseBackend::new(open_stores(matches));
+        let index_path = matches.get_one::<PathBuf>("index");
+        let out = matches.get_one::<PathBuf>("out").unwrap();
+
+        export::export(&backend, index_path.map(|p| p.as_path()), out);
+
+        return;
+    }
+
+    if let Some(("import", matches)) = matches.subcommand() {
+        let backend = SparseBackend::new(open_stores(matches));
+        let index_path = matches.get_one::<PathBuf>("index");
+        let input = matches.get_one::<PathBuf>("in").unwrap();
+
+        export::import(&backend, index_path.map(|p| p.as_path()), input);
+
+        return;
+    }
+
+    if let Some(("gc", matches)) = matches.subcommand() {
+        let backend = SparseBackend::new(open_stores(matches));
+        let index_path = matches.get_one::<PathBuf>("index");
+        let grace = Duration::from_secs(*matches.get_one::<u64>("grace").unwrap());
+
+        gc::run(&backend, index_path.map(|p| p.as_path()), grace);
+
+        return;
+    }
+
+    if let Some(("bench", matches)) = matches.subcommand() {
+        let backend = open_stores(matches);
+        let sizes: Vec<usize> = matches
+            .get_many::<u64>("size")
+            .unwrap()
+            .map(|size| *size as usize)
+            .collect();
+
+        bench::run(
+            &backend,
+            &sizes,
+            *matches.get_one::<u64>("count").unwrap() as usize,
+            *matches.get_one::<u64>("chunk-size").unwrap() as usize,
+            *matches.get_one::<u64>("concurrency").unwrap() as usize,
+        );
+
+        return;
+    }
+
+    if let Some(("empty-trash", matches)) = matches.subcommand() {
+        let backend = SparseBackend::new(open_stores(matches));
+        let index_path = matches.get_one::<PathBuf>("index");
+
+        trash::empty(&backend, index_path.map(|p| p.as_path()));
+
+        return;
+    }
+
+    let extra_options: Vec<MountOption> = matches
+        .get_many::<Vec<MountOption>>("options")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .cloned()
+        .collect();
+
+    // -o ro counts as --read-only, the FS refuses writes itself as well
+    let read_only = matches.get_flag("read-only") || extra_options.contains(&MountOption::RO);
+    let index_path = matches.get_one::<PathBuf>("index").cloned();
+    let max_file_size = *matches.get_one::<u64>("max-file-size").unwrap();
+    let capacity = matches.get_one::<u64>("channel-capacity").copied();
+    let retries = *matches.get_one::<u32>("retries").unwrap();
+    let concurrency = *matches.get_one::<u64>("concurrency").unwrap() as usize;
+    let chunk_size = *matches.get_one::<u64>("chunk-size").unwrap() as usize;
+    let attachment_limit = *matches.get_one::<u64>("attachment-limit").unwrap() as usize;
+
+    let upload_rate = matches.get_one::<u64>("max-upload-rate").copied();
+    let download_rate = matches.get_one::<u64>("max-download-rate").copied();
+
+    if chunk_size > attachment_limit {
+        eprintln!(
+            "error: --chunk-size of {} bytes exceeds the {} byte attachment limit, \
+             raise --attachment-limit if the server's boost tier allows more",
+            chunk_size, attachment_limit
+        );
+        process::exit(2);
+    }
+
+    if chunk_size < MIN_CHUNK_SIZE {
+        eprintln!(
+            "error: --chunk-size must be at least {} bytes",
+            MIN_CHUNK_SIZE
+        );
+        process::exit(2);
+    }
+
+    let options = mount_options(read_only, extra_options);
+
+    let backend: Box<dyn StorageBackend> = if matches.contains_id("store") {
+        Box::new(open_stores(&matches))
+    } else if matches.get_flag("dry-run") {
+        Box::new(DryRunBackend::default())
+    } else {
+        Box::new(MemBackend::default())
+    };
+
+    let profile = matches
+        .get_flag("profile")
+        .then(|| Arc::new(Profile::default()));
+
+    // Right around the store, so waits on the throttle and retries count
+    // toward the operation that ran into them instead
+    let backend: Box<dyn StorageBackend> = match &profile {
+        Some(profile) => Box::new(ProfileBackend::new(backend, profile.clone())),
+        None => backend,
+    };
+
+    let metrics = Arc::new(Metrics::default());
+
+    // Served once any forking is done, threads don't survive it
+    let metrics_listener = matches
+        .get_one::<SocketAddr>("metrics-addr")
+        .map(|addr| TcpListener::bind(addr).unwrap());
+
+    let backend = ThrottleBackend::new(backend, upload_rate, download_rate);
+    let backend = MetricsBackend::new(backend, metrics.clone());
+
+    // Reads served from the mirror skip the throttle and don't count as transfers
+    let backend: Box<dyn StorageBackend> = match matches.get_one::<PathBuf>("mirror") {
+        Some(dir) => Box::new(MirrorBackend::open(backend, dir.clone()).unwrap()),
+        None => Box::new(backend),
+    };
+
+    let backend: Box<dyn StorageBackend> = match matches.get_one::<PathBuf>("cache-dir") {
+        Some(dir) => {
+            let budget = *matches.get_one::<u64>("cache-size").unwrap();
+            Box::new(CacheBackend::open(backend, dir.clone(), budget).unwrap())
+        }
+        None => backend,
+    };
+
+    // Outermost, so holes never reach the store nor count as transfers
+    let mut fs = FS::new(SparseBackend::new(RetryBackend::new(backend, retries)));
+    fs.metrics = metrics.clone();
+    fs.profile = profile;
+    fs.max_file_size = max_file_size;
+    fs.max_dirty = matches.get_one::<u64>("max-dirty").copied();
+    fs.capacity = capacity;
+    fs.concurrency = concurrency;
+    fs.chunk_size = chunk_size;
+    fs.max_open_files = matches
+        .get_one::<u64>("max-open-files")
+        .map(|max| *max as usize);
+    fs.readahead = *matches.get_one::<u64>("readahead").unwrap() as usize;
+    fs.read_only = read_only;
+
+    // A daemon's terminal is gone before anything uploads
+    if matches.get_flag("progress") && !matches.get_flag("daemon") && io::stdout().is_terminal() {
+        fs.progress = Some(progress::bar());
+    }
+    fs.verify = matches.get_one::<String>("verify").unwrap() == "on";
+    fs.use_trash = matches.get_flag("trash");
+    fs.atime = AtimePolicy::parse(matches.get_one::<String>("atime").unwrap());
+    fs.index_path = index_path.clone();
+    fs.refresh_interval = matches
+        .get_one::<u64>("refresh")
+        .map(|seconds| Duration::from_secs(*seconds));
+    fs.checkpoint_interval = matches
+        .get_one::<u64>("checkpoint-interval")
+        .map(|seconds| Duration::from_secs(*seconds));
+
+    match Index::fetch(&fs.backend, index_path.as_deref()).unwrap() {
+        Some(index) => fs.restore_index(index),
+        None => match matches.get_one::<PathBuf>("seed") {
+            Some(dir) => fs.import_dir(dir).unwrap(),
+            None => {
+                fs.add_file("hello.txt", "Hello, World!".as_bytes())
+                    .unwrap();
+                fs.add_file("amongus.txt", "YOOO I DID IT LETS GOOO".as_bytes())
+                    .unwrap();
+            }
+        },
+    }
+
+    fs.configure_root(matches.get_one::<u16>("root-mode").copied());
+
+    // Without one, the index keeps the format it was found in
+    if let Some(format) = matches.get_one::<String>("index-format") {
+        fs.index_format = IndexFormat::parse(format);
+    }
+
+    let checkpoint_interval = fs.checkpoint_interval;
+    let fs = SharedFS::new(fs);
+
+    // The daemon only forks once the store answered and the mount is in place,
+    // so failing either is still reported on the terminal
+    let mut session = Session::new(fs.clone(), Path::new("./discordfs"), &options).unwrap();
+
+    if matches.get_flag("daemon") {
+        let log = matches.get_one::<PathBuf>("log-file");
+        daemon::daemonize(log.map(|p| p.as_path())).unwrap();
+    }
+
+    // Before the metrics, stats, checkpoint and extra mount threads start
+    let signals = block_shutdown_signals();
+
+    // Removed when it drops at the end of main, once the session has ended
+    let _pid_file = matches
+        .get_one::<PathBuf>("pid-file")
+        .map(|path| PidFile::create(path.clone()).unwrap());
+
+    if let Some(listener) = metrics_listener {
+        metrics::serve(metrics.clone(), listener);
+    }
+
+    // Stopped when it drops at the end of main, after the session has ended
+    let _stats = matches.get_flag("verbose-stats").then(|| {
+        let interval = *matches.get_one::<u64>("stats-interval").unwrap();
+        metrics::log_periodically(metrics.clone(), Duration::from_secs(interval))
+    });
+
+    // Stopped when it drops at the end of main, after destroy saved the index for good
+    let _checkpoints = checkpoint_interval.map(|interval| fs.checkpoint_periodically(interval));
+
+    // The extra mounts are unmounted when their sessions drop, after the main one ends
+    let mut read_only_options = options.clone();
+    read_only_options[0] = MountOption::RO;
+
+    let extra_mounts = [
+        ("also-mount", &options),
+        ("also-mount-ro", &read_only_options),
+    ];
+
+    let _sessions: Vec<BackgroundSession> = extra_mounts
+        .into_iter()
+        .flat_map(|(arg, options)| {
+            matches
+                .get_many::<PathBuf>(arg)
+                .into_iter()
+                .flatten()
+                .map(move |dir| (dir, options))
+        })
+        .map(|(dir, options)| fuser::spawn_mount2(fs.clone(), dir, options).unwrap())
+        .collect();
+
+    let mut unmounter = session.unmount_callable();
+    spawn_shutdown_handler(signals, move || {
+        if let Err(e) = unmounter.unmount() {
+            eprintln!("failed to unmount: {}", e);
+        }
+    });
 
-    fs.add_file("hello.txt", "Hello, World!".as_bytes());
-    fs.add_file("amongus.txt", "YOOO I DID IT LETS GOOO".as_bytes());
+    session.run().unwrap();
 
-    fuser::mount2(fs, "./discordfs", &options).unwrap();
+    // Dropping the session runs destroy if the kernel didn't, which has to
+    // finish before the pid file goes
+    drop(session);
 }
@@ -0,0 +1,134 @@
+use crate::backend::{ChunkId, StorageBackend};
+use crate::gc;
+use crate::index::{decode_name, Index};
+use fuser::FileAttr;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, UNIX_EPOCH};
+
+// What a stored chunk is part of, according to the index
+enum Owner {
+    File { name: String, n: usize, len: u64 },
+    Upload { ino: u64 },
+    // Only the generation an unreadable index falls back on still uses it
+    Previous,
+    Unreferenced,
+}
+
+fn owners(index: &Index, previous: Option<&Index>) -> HashMap<ChunkId, Owner> {
+    let mut owners: HashMap<ChunkId, Owner> = previous
+        .map(gc::referenced)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|id| (id, Owner::Previous))
+        .collect();
+
+    let files = index
+        .lookup_table
+        .iter()
+        .chain(index.trash.values().map(|(name, attr)| (name, attr)));
+
+    for (name, attr) in files {
+        let Some(chunks) = index.chunk_table.get(&attr.ino) else {
+            continue;
+        };
+
+        for (n, id) in chunks.iter().enumerate() {
+            let owner = Owner::File {
+                name: decode_name(name).to_string_lossy().into_owned(),
+                n,
+                len: chunk_len(index, attr, n),
+            };
+
+            owners.insert(*id, owner);
+        }
+    }
+
+    for (ino, upload) in &index.uploads {
+        for id in upload.uploaded() {
+            owners.insert(id, Owner::Upload { ino: *ino });
+        }
+    }
+
+    owners
+}
+
+// Sizes aren't stored, but every chunk but the last is full
+fn chunk_len(index: &Index, attr: &FileAttr, n: usize) -> u64 {
+    let chunk_size = index
+        .chunk_sizes
+        .get(&attr.ino)
+        .copied()
+        .unwrap_or(crate::DEFAULT_CHUNK_SIZE) as u64;
+
+    attr.size
+        .saturating_sub(n as u64 * chunk_size)
+        .min(chunk_size)
+}
+
+// Lists every stored chunk with what it backs. With `prune`, the ones the
+// index doesn't know about are deleted afterwards the way gc does it, so
+// chunks younger than the grace period are left alone.
+pub fn run<B: StorageBackend + ?Sized>(
+    backend: &B,
+    index_path: Option<&Path>,
+    prune: Option<Duration>,
+) {
+    let Some(index) = Index::fetch(backend, index_path).unwrap() else {
+        eprintln!("no index found");
+        process::exit(1);
+    };
+
+    let previous = Index::fetch_previous(backend).unwrap();
+    let owners = owners(&index, previous.as_ref());
+    let mut stored = backend.list_chunks().unwrap();
+    stored.sort_by_key(|(id, _)| *id);
+
+    let mut unreferenced = 0;
+
+    for (id, written) in &stored {
+        let written = written
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+
+        match owners.get(id).unwrap_or(&Owner::Unreferenced) {
+            Owner::File { name, n, len } => {
+                println!("{}\t{}\t{}\t{} chunk {}", id, written, len, name, n)
+            }
+            Owner::Upload { ino } => {
+                println!("{}\t{}\t-\tunfinished upload of inode {}", id, written, ino)
+            }
+            Owner::Previous => println!("{}\t{}\t-\tprevious index only", id, written),
+            Owner::Unreferenced => {
+                println!("{}\t{}\t-\tunreferenced", id, written);
+                unreferenced += 1;
+            }
+        }
+    }
+
+    println!("{} chunk(s), {} unreferenced", stored.len(), unreferenced);
+
+    if let Some(grace) = prune {
+        gc::run(backend, index_path, grace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FS;
+
+    #[test]
+    fn pruning_spares_chunks_of_uploads_in_flight() {
+        let mut fs = FS::new_for_test();
+        fs.save_index();
+        let young = fs.backend.put_chunk(b"young").unwrap();
+
+        run(&fs.backend, None, Some(Duration::from_secs(60)));
+        assert!(fs.backend.get_chunk(young).is_ok());
+
+        run(&fs.backend, None, Some(Duration::ZERO));
+        assert!(fs.backend.get_chunk(young).is_err());
+    }
+}
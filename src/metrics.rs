@@ -0,0 +1,301 @@
+use crate::backend::{ChunkId, StorageBackend};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+#[derive(Default)]
+pub struct Metrics {
+    pub uploads: AtomicU64,
+    pub upload_bytes: AtomicU64,
+    pub downloads: AtomicU64,
+    pub download_bytes: AtomicU64,
+    pub backend_errors: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub dirty_bytes: AtomicU64,
+    pub dirty_files: AtomicU64,
+    pub backpressure_flushes: AtomicU64,
+    pub in_flight: AtomicU64,
+}
+
+impl Metrics {
+    // Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let metrics = [
+            ("discordfs_uploads_total", "counter", &self.uploads),
+            (
+                "discordfs_upload_bytes_total",
+                "counter",
+                &self.upload_bytes,
+            ),
+            ("discordfs_downloads_total", "counter", &self.downloads),
+            (
+                "discordfs_download_bytes_total",
+                "counter",
+                &self.download_bytes,
+            ),
+            (
+                "discordfs_backend_errors_total",
+                "counter",
+                &self.backend_errors,
+            ),
+            ("discordfs_cache_hits_total", "counter", &self.cache_hits),
+            (
+                "discordfs_cache_misses_total",
+                "counter",
+                &self.cache_misses,
+            ),
+            ("discordfs_dirty_bytes", "gauge", &self.dirty_bytes),
+            ("discordfs_dirty_files", "gauge", &self.dirty_files),
+            (
+                "discordfs_backpressure_flushes_total",
+                "counter",
+                &self.backpressure_flushes,
+            ),
+            (
+                "discordfs_backend_requests_in_flight",
+                "gauge",
+                &self.in_flight,
+            ),
+        ];
+
+        for (name, kind, value) in metrics {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        out
+    }
+
+    pub fn summary(&self) -> String {
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let misses = self.cache_misses.load(Ordering::Relaxed);
+
+        let hit_rate = match hits + misses {
+            0 => "-".to_string(),
+            reads => format!("{:.1}%", hits as f64 * 100.0 / reads as f64),
+        };
+
+        let mib = |bytes: &AtomicU64| bytes.load(Ordering::Relaxed) as f64 / (1 << 20) as f64;
+
+        format!(
+            "cache hit rate {}, {:.1} MiB up, {:.1} MiB down, {} dirty file(s) ({:.1} MiB), {} request(s) in flight",
+            hit_rate,
+            mib(&self.upload_bytes),
+            mib(&self.download_bytes),
+            self.dirty_files.load(Ordering::Relaxed),
+            mib(&self.dirty_bytes),
+            self.in_flight.load(Ordering::Relaxed)
+        )
+    }
+}
+
+// Prints a summary every `interval` until the returned logger is dropped
+pub fn log_periodically(metrics: Arc<Metrics>, interval: Duration) -> StatsLogger {
+    let (stop, stopped) = mpsc::channel::<()>();
+
+    let thread = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            eprintln!("stats: {}", metrics.summary());
+        }
+    });
+
+    StatsLogger {
+        stop: Some(stop),
+        thread: Some(thread),
+    }
+}
+
+pub struct StatsLogger {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for StatsLogger {
+    // Hanging up the channel wakes the thread, which then exits
+    fn drop(&mut self) {
+        self.stop.take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Answers every request on `addr` with the current metrics, whatever the path
+// Bound separately, so a daemon can report a taken address before it forks
+pub fn serve(metrics: Arc<Metrics>, listener: TcpListener) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(&metrics, stream));
+
+            if let Err(e) = result {
+                eprintln!("failed to serve metrics: {}", e);
+            }
+        }
+    });
+}
+
+fn respond(metrics: &Metrics, mut stream: TcpStream) -> io::Result<()> {
+    // The request itself doesn't matter, but it has to be read before replying
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let body = metrics.render();
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+// Counts the traffic going through the wrapped backend
+pub struct MetricsBackend<B> {
+    inner: B,
+    metrics: Arc<Metrics>,
+}
+
+impl<B: StorageBackend> MetricsBackend<B> {
+    pub fn new(inner: B, metrics: Arc<Metrics>) -> Self {
+        MetricsBackend { inner, metrics }
+    }
+
+    fn count<T>(&self, op: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        self.metrics.in_flight.fetch_add(1, Ordering::Relaxed);
+        let result = op();
+        self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        if result.is_err() {
+            self.metrics.backend_errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    fn uploaded(&self, bytes: usize) {
+        self.metrics.uploads.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .upload_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn downloaded(&self, bytes: usize) {
+        self.metrics.downloads.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .download_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for MetricsBackend<B> {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        let id = self.count(|| self.inner.put_chunk(data))?;
+        self.uploaded(data.len());
+
+        Ok(id)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        let chunk = self.count(|| self.inner.get_chunk(id))?;
+        self.downloaded(chunk.len());
+
+        Ok(chunk)
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        self.count(|| self.inner.delete_chunk(id))
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.count(|| self.inner.load_index())
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        self.count(|| self.inner.save_index(index))
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        self.count(|| self.inner.save_index_if(expected, index))
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.count(|| self.inner.load_previous_index())
+    }
+
+    fn store_id(&self) -> Option<String> {
+        self.inner.store_id()
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        self.count(|| self.inner.list_chunks())
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let data = self.count(|| self.inner.get_chunk_range(id, range))?;
+        self.downloaded(data.len());
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestBackend;
+    use std::time::Instant;
+
+    #[test]
+    fn the_summary_covers_cache_transfers_and_dirty_data() {
+        let metrics = Metrics::default();
+        assert!(metrics.summary().starts_with("cache hit rate -,"));
+
+        metrics.cache_hits.store(3, Ordering::Relaxed);
+        metrics.cache_misses.store(1, Ordering::Relaxed);
+        metrics.upload_bytes.store(3 << 20, Ordering::Relaxed);
+        metrics.dirty_files.store(2, Ordering::Relaxed);
+        metrics.dirty_bytes.store(1 << 19, Ordering::Relaxed);
+
+        assert_eq!(
+            metrics.summary(),
+            "cache hit rate 75.0%, 3.0 MiB up, 0.0 MiB down, 2 dirty file(s) (0.5 MiB), 0 request(s) in flight"
+        );
+    }
+
+    #[test]
+    fn requests_count_as_in_flight_while_they_run() {
+        let metrics = Arc::new(Metrics::default());
+        let backend = MetricsBackend::new(TestBackend::default(), metrics.clone());
+        backend.inner.set_latency(Duration::from_millis(200));
+
+        thread::scope(|scope| {
+            scope.spawn(|| backend.put_chunk(b"hello").unwrap());
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(metrics.in_flight.load(Ordering::Relaxed), 1);
+        });
+
+        assert_eq!(metrics.in_flight.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.upload_bytes.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn dropping_the_logger_stops_it_right_away() {
+        let logger = log_periodically(Arc::new(Metrics::default()), Duration::from_secs(60));
+        let start = Instant::now();
+        drop(logger);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::backend::{self, ChunkId, StorageBackend};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+// Keeps a local copy of every chunk next to the wrapped backend. Reads are
+// served from the copy when there is one, the wrapped backend stays the
+// authority on what exists.
+pub struct MirrorBackend<B> {
+    inner: B,
+    dir: PathBuf,
+}
+
+impl<B: StorageBackend> MirrorBackend<B> {
+    // Drops local copies of chunks the wrapped backend no longer has. Missing
+    // copies are filled in as they're read.
+    pub fn open(inner: B, dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let stored: HashSet<ChunkId> = inner.list_chunks()?.into_iter().map(|(id, _)| id).collect();
+        let mut mirrored = 0;
+        let mut dropped = 0;
+
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+
+            let Some(id) = entry.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+
+            if stored.contains(&id) {
+                mirrored += 1;
+            } else {
+                fs::remove_file(entry.path())?;
+                dropped += 1;
+            }
+        }
+
+        eprintln!(
+            "mirror: {} of {} chunk(s) available locally, {} stale copies dropped",
+            mirrored,
+            stored.len(),
+            dropped
+        );
+
+        Ok(MirrorBackend { inner, dir })
+    }
+
+    fn chunk_path(&self, id: ChunkId) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    // The chunk is already safe in the wrapped backend, a failed copy only costs a fetch later
+    fn store_copy(&self, id: ChunkId, data: &[u8]) {
+        let tmp = self.chunk_path(id).with_extension("tmp");
+
+        if let Err(e) = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, self.chunk_path(id))) {
+            eprintln!("mirror: failed to copy chunk {}: {}", id, e);
+        }
+    }
+
+    fn remove_copy(&self, id: ChunkId) {
+        match fs::remove_file(self.chunk_path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                eprintln!("mirror: failed to remove the copy of chunk {}: {}", id, e);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for MirrorBackend<B> {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        let id = self.inner.put_chunk(data)?;
+        self.store_copy(id, data);
+
+        Ok(id)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        match fs::read(self.chunk_path(id)) {
+            Ok(data) => return Ok(data),
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                eprintln!("mirror: failed to read the copy of chunk {}: {}", id, e);
+            }
+            Err(_) => {}
+        }
+
+        let data = self.inner.get_chunk(id)?;
+        self.store_copy(id, &data);
+
+        Ok(data)
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        self.inner.delete_chunk(id)?;
+        self.remove_copy(id);
+
+        Ok(())
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.load_index()
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        self.inner.save_index(index)
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        self.inner.save_index_if(expected, index)
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.load_previous_index()
+    }
+
+    fn store_id(&self) -> Option<String> {
+        self.inner.store_id()
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        self.inner.list_chunks()
+    }
+
+    // A missing copy is only filled in by whole reads
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        match fs::read(self.chunk_path(id)) {
+            Ok(data) => return Ok(data[backend::clamp(range, data.len())].to_vec()),
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                eprintln!("mirror: failed to read the copy of chunk {}: {}", id, e);
+            }
+            Err(_) => {}
+        }
+
+        self.inner.get_chunk_range(id, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Call, TestBackend};
+
+    #[test]
+    fn reads_come_from_the_local_copy() {
+        let dir = testing::temp_dir();
+        let mirror = MirrorBackend::open(TestBackend::default(), dir.clone()).unwrap();
+        let id = mirror.put_chunk(b"hello").unwrap();
+
+        assert_eq!(mirror.get_chunk(id).unwrap(), b"hello");
+        assert!(!mirror.inner.calls().contains(&Call::Get { id }));
+
+        // A lost copy is fetched once and kept again
+        fs::remove_file(dir.join(id.to_string())).unwrap();
+        assert_eq!(mirror.get_chunk(id).unwrap(), b"hello");
+        assert_eq!(mirror.get_chunk(id).unwrap(), b"hello");
+        let gets = mirror.inner.calls().into_iter();
+        assert_eq!(gets.filter(|call| *call == Call::Get { id }).count(), 1);
+    }
+
+    #[test]
+    fn opening_drops_copies_the_backend_no_longer_has() {
+        let dir = testing::temp_dir();
+        let mirror = MirrorBackend::open(TestBackend::default(), dir.clone()).unwrap();
+        let kept = mirror.put_chunk(b"kept").unwrap();
+        let lost = mirror.put_chunk(b"lost").unwrap();
+        mirror.inner.lose_chunk(lost);
+
+        let mirror = MirrorBackend::open(mirror.inner, dir.clone()).unwrap();
+        assert!(dir.join(kept.to_string()).exists());
+        assert!(!dir.join(lost.to_string()).exists());
+        assert!(mirror.get_chunk(lost).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+use crate::backend::{ChunkId, StorageBackend};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+// Bucket i holds latencies under 2^i microseconds, the last one everything longer
+const BUCKETS: usize = 32;
+
+// Latencies by power of two, enough to tell a millisecond from a second
+// without keeping every sample
+struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    // The upper bound of the bucket the quantile falls in, never above the slowest
+    fn quantile(&self, q: f64) -> Duration {
+        let rank = ((self.count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+
+            if seen >= rank {
+                return Duration::from_micros(1 << i).min(self.max);
+            }
+        }
+
+        self.max
+    }
+}
+
+// Per-operation latencies for --profile, summed up on unmount
+#[derive(Default)]
+pub struct Profile {
+    ops: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Profile {
+    pub fn record(&self, op: &'static str, elapsed: Duration) {
+        self.ops
+            .lock()
+            .unwrap()
+            .entry(op)
+            .or_default()
+            .record(elapsed);
+    }
+
+    // Records the time until the returned timer is dropped
+    pub fn time(self: &Arc<Self>, op: &'static str) -> Timer {
+        Timer {
+            profile: self.clone(),
+            op,
+            started: Instant::now(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn count(&self, op: &str) -> u64 {
+        self.ops.lock().unwrap().get(op).map_or(0, |ops| ops.count)
+    }
+
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+
+        for (op, histogram) in self.ops.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{:<16} {:>8} op(s), mean {:?}, p50 {:?}, p99 {:?}, max {:?}",
+                op,
+                histogram.count,
+                histogram.total.div_f64(histogram.count as f64),
+                histogram.quantile(0.5),
+                histogram.quantile(0.99),
+                histogram.max
+            );
+        }
+
+        out
+    }
+}
+
+pub struct Timer {
+    profile: Arc<Profile>,
+    op: &'static str,
+    started: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.profile.record(self.op, self.started.elapsed());
+    }
+}
+
+// Times the calls to the wrapped backend, to tell the network from the
+// filesystem's own work
+pub struct ProfileBackend<B> {
+    inner: B,
+    profile: Arc<Profile>,
+}
+
+impl<B: StorageBackend> ProfileBackend<B> {
+    pub fn new(inner: B, profile: Arc<Profile>) -> Self {
+        ProfileBackend { inner, profile }
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for ProfileBackend<B> {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        let _timer = self.profile.time("backend put");
+        self.inner.put_chunk(data)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        let _timer = self.profile.time("backend get");
+        self.inner.get_chunk(id)
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        let _timer = self.profile.time("backend delete");
+        self.inner.delete_chunk(id)
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        let _timer = self.profile.time("backend load");
+        self.inner.load_index()
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        let _timer = self.profile.time("backend save");
+        self.inner.save_index(index)
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        let _timer = self.profile.time("backend save");
+        self.inner.save_index_if(expected, index)
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        let _timer = self.profile.time("backend load");
+        self.inner.load_previous_index()
+    }
+
+    fn store_id(&self) -> Option<String> {
+        self.inner.store_id()
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        let _timer = self.profile.time("backend list");
+        self.inner.list_chunks()
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let _timer = self.profile.time("backend get");
+        self.inner.get_chunk_range(id, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestBackend;
+    use crate::{Caller, FS};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn every_operation_is_counted_once() {
+        let profile = Arc::new(Profile::default());
+        let mut fs = FS::new(ProfileBackend::new(TestBackend::default(), profile.clone()));
+        fs.profile = Some(profile.clone());
+
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new("file"), 0o644, 0)
+            .unwrap()
+            .ino;
+
+        for i in 0..5 {
+            fs.do_write(ino, i * 10, &[1; 10]).unwrap();
+        }
+
+        fs.do_release(ino, 0).unwrap();
+        fs.do_read(0, ino, 0, 50).unwrap();
+        fs.do_read(0, ino, 10, 10).unwrap();
+        assert_eq!(fs.do_lookup(1, OsStr::new("missing")), Err(libc::ENOENT));
+
+        assert_eq!(profile.count("create"), 1);
+        assert_eq!(profile.count("write"), 5);
+        assert_eq!(profile.count("release"), 1);
+        assert_eq!(profile.count("read"), 2);
+        assert_eq!(profile.count("lookup"), 1);
+        assert_eq!(profile.count("backend put"), 1);
+        assert_eq!(profile.count("backend get"), 1);
+        assert!(profile.summary().contains("write"));
+    }
+
+    #[test]
+    fn quantiles_come_from_the_buckets() {
+        let mut histogram = Histogram::default();
+
+        for _ in 0..98 {
+            histogram.record(Duration::from_micros(100));
+        }
+
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_millis(20));
+
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(128));
+        assert_eq!(histogram.quantile(0.99), Duration::from_micros(8192));
+        assert_eq!(histogram.quantile(1.0), Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,81 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// How far a flush of one file has got
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub ino: u64,
+    pub chunks: usize,
+    pub total_chunks: usize,
+    pub bytes: u64,
+    pub total_bytes: u64,
+    pub started: Instant,
+}
+
+pub type ProgressHook = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+// Counts the finished chunks of one upload, which complete on several threads at once
+pub struct Tracker<'a> {
+    hook: &'a ProgressHook,
+    progress: Mutex<Progress>,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn start(hook: &'a ProgressHook, progress: Progress) -> Self {
+        hook(&progress);
+
+        Tracker {
+            hook,
+            progress: Mutex::new(progress),
+        }
+    }
+
+    pub fn chunk_done(&self, bytes: usize) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.chunks += 1;
+        progress.bytes += bytes as u64;
+
+        (self.hook)(&progress);
+    }
+}
+
+// Redraws a single line on stdout as chunks finish
+pub fn bar() -> ProgressHook {
+    Arc::new(|progress| {
+        let fraction = match progress.total_bytes {
+            0 => 1.0,
+            total => progress.bytes as f64 / total as f64,
+        };
+
+        let filled = (fraction * 30.0) as usize;
+        let elapsed = progress.started.elapsed().as_secs_f64();
+
+        let eta = if fraction > 0.0 && fraction < 1.0 {
+            format!(", {:.0}s left", elapsed / fraction - elapsed)
+        } else {
+            String::new()
+        };
+
+        let mut stdout = io::stdout().lock();
+        let _ = write!(
+            stdout,
+            "\rinode {} [{}{}] {:>3.0}% {:.1}/{:.1} MiB, {}/{} chunks{}\x1b[K",
+            progress.ino,
+            "#".repeat(filled),
+            "-".repeat(30 - filled),
+            fraction * 100.0,
+            progress.bytes as f64 / (1 << 20) as f64,
+            progress.total_bytes as f64 / (1 << 20) as f64,
+            progress.chunks,
+            progress.total_chunks,
+            eta
+        );
+
+        if progress.chunks == progress.total_chunks {
+            let _ = writeln!(stdout);
+        }
+
+        let _ = stdout.flush();
+    })
+}
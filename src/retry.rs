@@ -0,0 +1,173 @@
+use crate::backend::{ChunkId, StorageBackend};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const BASE_DELAY: Duration = Duration::from_millis(250);
+
+// Retries transient failures of the wrapped backend with exponential backoff
+pub struct RetryBackend<B> {
+    inner: B,
+    max_retries: u32,
+}
+
+impl<B: StorageBackend> RetryBackend<B> {
+    pub fn new(inner: B, max_retries: u32) -> Self {
+        RetryBackend { inner, max_retries }
+    }
+
+    fn retry<T>(
+        &self,
+        what: &str,
+        retryable: fn(&io::Error) -> bool,
+        mut op: impl FnMut() -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut attempt = 0;
+
+        loop {
+            match op() {
+                Err(e) if attempt < self.max_retries && retryable(&e) => {
+                    let delay = backoff(attempt);
+                    eprintln!("{} failed: {}, retrying in {:?}", what, e, delay);
+
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt));
+    let jitter = RandomState::new().build_hasher().finish() % (delay.as_millis() as u64 / 2 + 1);
+
+    delay + Duration::from_millis(jitter)
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+// An upload that failed mid-flight may still have landed, and retrying it
+// would leave a duplicate behind. Only retry when nothing was sent at all.
+fn is_unsent(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::ConnectionRefused
+}
+
+impl<B: StorageBackend> StorageBackend for RetryBackend<B> {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        self.retry("uploading a chunk", is_unsent, || {
+            self.inner.put_chunk(data)
+        })
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        self.retry("fetching a chunk", is_transient, || {
+            self.inner.get_chunk(id)
+        })
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        self.retry("deleting a chunk", is_transient, || {
+            self.inner.delete_chunk(id)
+        })
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.retry("loading the index", is_transient, || {
+            self.inner.load_index()
+        })
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        self.retry("saving the index", is_transient, || {
+            self.inner.save_index(index)
+        })
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        self.retry("saving the index", is_transient, || {
+            self.inner.save_index_if(expected, index)
+        })
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.retry("loading the previous index", is_transient, || {
+            self.inner.load_previous_index()
+        })
+    }
+
+    fn store_id(&self) -> Option<String> {
+        self.inner.store_id()
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        self.retry("listing chunks", is_transient, || self.inner.list_chunks())
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        self.retry("fetching part of a chunk", is_transient, || {
+            self.inner.get_chunk_range(id, range.clone())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Call, TestBackend};
+
+    #[test]
+    fn transient_failures_are_retried_until_they_pass() {
+        let backend = RetryBackend::new(TestBackend::default(), 3);
+        let id = backend.put_chunk(b"hello").unwrap();
+
+        backend.inner.fail_next(ErrorKind::TimedOut);
+        backend.inner.fail_next(ErrorKind::TimedOut);
+        assert_eq!(backend.get_chunk(id).unwrap(), b"hello");
+        assert_eq!(backend.inner.calls().last(), Some(&Call::Get { id }));
+    }
+
+    #[test]
+    fn retries_give_up_after_the_limit() {
+        let backend = RetryBackend::new(TestBackend::default(), 1);
+        let id = backend.put_chunk(b"hello").unwrap();
+
+        for _ in 0..3 {
+            backend.inner.fail_next(ErrorKind::TimedOut);
+        }
+
+        let err = backend.get_chunk(id).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        // One attempt plus one retry, so a single failure is left over
+        assert!(backend.inner.get_chunk(id).is_err());
+        assert!(backend.inner.get_chunk(id).is_ok());
+    }
+
+    #[test]
+    fn uploads_are_only_retried_when_nothing_was_sent() {
+        let backend = RetryBackend::new(TestBackend::default(), 3);
+
+        backend.inner.fail_next(ErrorKind::TimedOut);
+        assert!(backend.put_chunk(b"hello").is_err());
+
+        backend.inner.fail_next(ErrorKind::ConnectionRefused);
+        assert!(backend.put_chunk(b"hello").is_ok());
+        assert_eq!(backend.inner.uploaded_bytes(), 5);
+    }
+}
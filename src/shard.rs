@@ -0,0 +1,197 @@
+use crate::backend::{ChunkId, StorageBackend};
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+// Which store a chunk lives in is kept in the bits of its id below the one
+// SparseBackend uses, so the index records it along with the chunk. Chunks of
+// the first store keep the ids it handed out, a store that gains shards still
+// reads as before.
+const SHARD_SHIFT: u32 = 55;
+const SHARD_MASK: ChunkId = 0xff << SHARD_SHIFT;
+pub const MAX_SHARDS: usize = 1 << 8;
+
+// Spreads chunks over several stores in turn, to get past what one of them
+// can hold or take at a time. The first store also keeps the index. Ids only
+// mean something with the stores in the same order, so it has to stay the same.
+pub struct ShardBackend<B> {
+    shards: Vec<B>,
+    next: AtomicUsize,
+}
+
+impl<B: StorageBackend> ShardBackend<B> {
+    pub fn new(shards: Vec<B>) -> Self {
+        assert!(!shards.is_empty() && shards.len() <= MAX_SHARDS);
+
+        ShardBackend {
+            shards,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn shard(&self, id: ChunkId) -> io::Result<(&B, ChunkId)> {
+        let n = ((id & SHARD_MASK) >> SHARD_SHIFT) as usize;
+
+        match self.shards.get(n) {
+            Some(shard) => Ok((shard, id & !SHARD_MASK)),
+            None => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "chunk {} is in store {}, only {} given",
+                    id,
+                    n + 1,
+                    self.shards.len()
+                ),
+            )),
+        }
+    }
+}
+
+fn sharded(n: usize, id: ChunkId) -> io::Result<ChunkId> {
+    if id & SHARD_MASK != 0 {
+        return Err(io::Error::other(format!(
+            "chunk id {} is too large to record its store in",
+            id
+        )));
+    }
+
+    Ok(id | (n as ChunkId) << SHARD_SHIFT)
+}
+
+impl<B: StorageBackend> StorageBackend for ShardBackend<B> {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        let n = self.next.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        let id = self.shards[n].put_chunk(data)?;
+
+        sharded(n, id)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        let (shard, id) = self.shard(id)?;
+        shard.get_chunk(id)
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        let (shard, id) = self.shard(id)?;
+        shard.delete_chunk(id)
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.shards[0].load_index()
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        self.shards[0].save_index(index)
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        self.shards[0].save_index_if(expected, index)
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.shards[0].load_previous_index()
+    }
+
+    // Only the same stores in the same order are the same store
+    fn store_id(&self) -> Option<String> {
+        let ids: Option<Vec<String>> = self.shards.iter().map(|shard| shard.store_id()).collect();
+        ids.map(|ids| ids.join(","))
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        let mut chunks = Vec::new();
+
+        for (n, shard) in self.shards.iter().enumerate() {
+            for (id, written) in shard.list_chunks()? {
+                chunks.push((sharded(n, id)?, written));
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let (shard, id) = self.shard(id)?;
+        shard.get_chunk_range(id, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemBackend;
+    use crate::index::Index;
+    use crate::sparse::SparseBackend;
+    use crate::{Caller, FS};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn chunks_are_spread_evenly_and_all_read_back() {
+        let backend = ShardBackend::new(vec![MemBackend::default(), MemBackend::default()]);
+
+        let chunks: Vec<(ChunkId, Vec<u8>)> = (0..100u32)
+            .map(|i| {
+                let data = i.to_le_bytes().to_vec();
+                (backend.put_chunk(&data).unwrap(), data)
+            })
+            .collect();
+
+        for shard in &backend.shards {
+            assert_eq!(shard.list_chunks().unwrap().len(), 50);
+        }
+
+        for (id, data) in &chunks {
+            assert_eq!(&backend.get_chunk(*id).unwrap(), data);
+        }
+
+        // The first store's ids are left as they are
+        let (first, _) = &chunks[0];
+        assert_eq!(
+            backend.shards[0].get_chunk(*first).unwrap(),
+            0u32.to_le_bytes()
+        );
+
+        let (id, _) = &chunks[1];
+        backend.delete_chunk(*id).unwrap();
+        assert!(backend.get_chunk(*id).is_err());
+        assert_eq!(backend.shards[1].list_chunks().unwrap().len(), 49);
+        assert_eq!(backend.list_chunks().unwrap().len(), 99);
+
+        // Fewer stores than the chunk was written to
+        let one = ShardBackend::new(vec![MemBackend::default()]);
+        let error = one.get_chunk(*id).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn files_read_back_across_stores() {
+        let shards = ShardBackend::new(vec![MemBackend::default(), MemBackend::default()]);
+        let mut fs = FS::new(SparseBackend::new(shards));
+        fs.chunk_size = crate::MIN_CHUNK_SIZE;
+
+        let data: Vec<u8> = (0..4 * crate::MIN_CHUNK_SIZE)
+            .map(|i| (i % 251) as u8 + 1)
+            .collect();
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new("spread.bin"), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, &data).unwrap();
+        fs.do_release(ino, 0).unwrap();
+
+        let index = Index::fetch(&fs.backend, None).unwrap().unwrap();
+        let stores: Vec<ChunkId> = index.chunk_table[&ino]
+            .iter()
+            .map(|id| id >> SHARD_SHIFT)
+            .collect();
+        assert_eq!(stores.iter().filter(|&&n| n == 0).count(), 2);
+        assert_eq!(stores.iter().filter(|&&n| n == 1).count(), 2);
+
+        fs.data_table.clear();
+        fs.chunk_cache.clear();
+        fs.chunk_cache_order.clear();
+        let read = fs.do_read(0, ino, 0, data.len() as u32).unwrap();
+        assert_eq!(read, data);
+    }
+}
@@ -0,0 +1,438 @@
+use crate::backend::StorageBackend;
+use crate::FS;
+use fuser::{
+    Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, Request,
+    TimeOrNow,
+};
+use libc::c_int;
+use std::ffi::OsStr;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+// Lets several mounts serve the same filesystem. Each request holds the lock
+// while it runs, so the mounts see each other's changes right away.
+pub struct SharedFS<B: StorageBackend>(Arc<Mutex<FS<B>>>);
+
+impl<B: StorageBackend + 'static> SharedFS<B> {
+    pub fn new(fs: FS<B>) -> Self {
+        SharedFS(Arc::new(Mutex::new(fs)))
+    }
+
+    // Saves the index every `interval` if it changed, until the returned
+    // handle is dropped
+    pub fn checkpoint_periodically(&self, interval: Duration) -> Checkpoints {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let fs = self.0.clone();
+
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                fs.lock().unwrap().checkpoint();
+            }
+        });
+
+        Checkpoints {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+pub struct Checkpoints {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Checkpoints {
+    // Hanging up the channel wakes the thread, which then exits
+    fn drop(&mut self) {
+        self.stop.take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<B: StorageBackend> Clone for SharedFS<B> {
+    fn clone(&self) -> Self {
+        SharedFS(self.0.clone())
+    }
+}
+
+impl<B: StorageBackend> Filesystem for SharedFS<B> {
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        self.0.lock().unwrap().init(req, config)
+    }
+
+    fn destroy(&mut self) {
+        self.0.lock().unwrap().destroy()
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.0.lock().unwrap().lookup(req, parent, name, reply)
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.0.lock().unwrap().forget(req, ino, nlookup)
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.0.lock().unwrap().getattr(req, ino, reply)
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .read(req, ino, fh, offset, size, flags, lock, reply)
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.0.lock().unwrap().readdir(req, ino, fh, offset, reply)
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .mknod(req, parent, name, mode, umask, rdev, reply)
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .create(req, parent, name, mode, umask, flags, reply)
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.0.lock().unwrap().unlink(req, parent, name, reply)
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.0.lock().unwrap().open(req, ino, flags, reply)
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.0.lock().unwrap().access(req, ino, mask, reply)
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.0.lock().unwrap().opendir(req, ino, flags, reply)
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        self.0
+            .lock()
+            .unwrap()
+            .releasedir(req, ino, fh, flags, reply)
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.0.lock().unwrap().write(
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        )
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.0
+            .lock()
+            .unwrap()
+            .flush(req, ino, fh, lock_owner, reply)
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .release(req, ino, fh, flags, lock_owner, flush, reply)
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.0.lock().unwrap().setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+            flags, reply,
+        )
+    }
+
+    fn getlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
+    }
+
+    fn setlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .fallocate(req, ino, fh, offset, length, mode, reply)
+    }
+
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        self.0.lock().unwrap().bmap(req, ino, blocksize, idx, reply)
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        self.0.lock().unwrap().statfs(req, ino, reply)
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        self.0
+            .lock()
+            .unwrap()
+            .ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Call};
+    use crate::Caller;
+
+    #[test]
+    fn checkpoints_run_until_dropped() {
+        let mut fs = FS::new_for_test();
+        fs.checkpoint_interval = Some(Duration::from_millis(20));
+        fs.save_index();
+        let fs = SharedFS::new(fs);
+
+        let checkpoints = fs.checkpoint_periodically(Duration::from_millis(20));
+        thread::sleep(Duration::from_millis(200));
+        drop(checkpoints);
+
+        let fs = fs.0.lock().unwrap();
+        assert!(!fs.index_dirty);
+        let calls = fs.backend().calls();
+        let saves = calls
+            .iter()
+            .filter(|call| matches!(call, Call::SaveIndex { .. }));
+        assert_eq!(saves.count(), 1);
+    }
+
+    #[test]
+    fn concurrent_writes_to_one_file_all_land() {
+        let mut fs = FS::new_for_test();
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new("shared.bin"), 0o644, 0)
+            .unwrap()
+            .ino;
+        let fs = SharedFS::new(fs);
+
+        // Interleaved 1000-byte blocks, with later blocks often growing the
+        // file past ones that haven't been written yet
+        thread::scope(|scope| {
+            for writer in 0..8u8 {
+                let fs = fs.clone();
+
+                scope.spawn(move || {
+                    for block in (writer as usize..800).step_by(8) {
+                        let data = vec![writer + 1; 1000];
+                        let mut fs = fs.0.lock().unwrap();
+                        fs.do_write(ino, (block * 1000) as i64, &data).unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut fs = fs.0.lock().unwrap();
+        let data = fs.do_read(0, ino, 0, 800 * 1000).unwrap();
+        assert_eq!(data.len(), 800 * 1000);
+
+        for (block, bytes) in data.chunks(1000).enumerate() {
+            let writer = (block % 8) as u8;
+            assert!(
+                bytes.iter().all(|&byte| byte == writer + 1),
+                "block {}",
+                block
+            );
+        }
+    }
+
+    // What the two mounts' session threads do for a create, write and close
+    // on one side and a lookup, open and read on the other, without FUSE
+    #[test]
+    fn writes_through_one_clone_show_in_the_other() {
+        let one = SharedFS::new(FS::new_for_test());
+        let other = one.clone();
+        let name = OsStr::new("hello.txt");
+
+        let missing = thread::spawn({
+            let other = other.clone();
+            move || {
+                other
+                    .0
+                    .lock()
+                    .unwrap()
+                    .do_lookup(crate::ROOT_INO, name)
+                    .err()
+            }
+        });
+        assert_eq!(missing.join().unwrap(), Some(libc::ENOENT));
+
+        thread::spawn(move || {
+            let mut fs = one.0.lock().unwrap();
+            let ino = fs.do_create(Caller::mounter(), name, 0o644, 0).unwrap().ino;
+            let fh = fs.open_handle(ino);
+            fs.do_write(ino, 0, b"Hello, World!").unwrap();
+            fs.do_release(ino, fh).unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let read = thread::spawn(move || {
+            let mut fs = other.0.lock().unwrap();
+            let attr = fs.do_lookup(crate::ROOT_INO, name).unwrap();
+            let fh = fs
+                .do_open(Caller::mounter(), attr.ino, libc::O_RDONLY)
+                .unwrap();
+            let data = fs.do_read(fh, attr.ino, 0, attr.size as u32).unwrap();
+            fs.do_release(attr.ino, fh).unwrap();
+            data
+        });
+        assert_eq!(read.join().unwrap(), b"Hello, World!");
+    }
+
+    #[test]
+    #[ignore = "needs FUSE and permission to mount"]
+    fn writes_through_one_mount_show_in_the_other() {
+        let fs = SharedFS::new(FS::new_for_test());
+        let (first, one) = testing::mount(fs.clone()).unwrap();
+        let (second, other) = testing::mount(fs).unwrap();
+
+        std::fs::write(one.join("hello.txt"), b"Hello, World!").unwrap();
+        assert_eq!(
+            std::fs::read(other.join("hello.txt")).unwrap(),
+            b"Hello, World!"
+        );
+
+        drop(first);
+        drop(second);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::backend::{self, ChunkId, StorageBackend};
+use std::io;
+use std::ops::Range;
+use std::time::SystemTime;
+
+// Ids with the top bit set stand for that many zero bytes, no backend ever
+// hands out ids that large
+const ZERO_CHUNK: ChunkId = 1 << 63;
+
+fn is_zero_chunk(id: ChunkId) -> bool {
+    id & ZERO_CHUNK != 0
+}
+
+// Keeps chunks of nothing but zeros, like the holes of sparse files, out of
+// the wrapped backend. They're recorded in the index under an id holding
+// their length and materialized again on read.
+pub struct SparseBackend<B> {
+    inner: B,
+}
+
+impl<B: StorageBackend> SparseBackend<B> {
+    pub fn new(inner: B) -> Self {
+        SparseBackend { inner }
+    }
+}
+
+fn zero_chunk(data: &[u8]) -> Option<ChunkId> {
+    let zeros = !data.is_empty() && data.iter().all(|&byte| byte == 0);
+    zeros.then_some(ZERO_CHUNK | data.len() as u64)
+}
+
+impl<B: StorageBackend> StorageBackend for SparseBackend<B> {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        match zero_chunk(data) {
+            Some(id) => Ok(id),
+            None => self.inner.put_chunk(data),
+        }
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        if is_zero_chunk(id) {
+            return Ok(vec![0; (id & !ZERO_CHUNK) as usize]);
+        }
+
+        self.inner.get_chunk(id)
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        if is_zero_chunk(id) {
+            return Ok(());
+        }
+
+        self.inner.delete_chunk(id)
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.load_index()
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        self.inner.save_index(index)
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        self.inner.save_index_if(expected, index)
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.load_previous_index()
+    }
+
+    fn store_id(&self) -> Option<String> {
+        self.inner.store_id()
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        self.inner.list_chunks()
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        if is_zero_chunk(id) {
+            let range = backend::clamp(range, (id & !ZERO_CHUNK) as usize);
+            return Ok(vec![0; range.len()]);
+        }
+
+        self.inner.get_chunk_range(id, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Call, TestBackend};
+    use crate::{Caller, FS};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn zero_chunks_never_reach_the_store() {
+        let backend = SparseBackend::new(TestBackend::default());
+
+        let zeros = backend.put_chunk(&[0; 1000]).unwrap();
+        assert!(is_zero_chunk(zeros));
+        assert_eq!(backend.get_chunk(zeros).unwrap(), vec![0; 1000]);
+        backend.delete_chunk(zeros).unwrap();
+        assert!(backend.inner.calls().is_empty());
+
+        // Going from data to zeros and back, only the data is ever sent
+        let id = backend.put_chunk(b"hello").unwrap();
+        assert!(!is_zero_chunk(id));
+        assert!(is_zero_chunk(backend.put_chunk(&[0; 5]).unwrap()));
+        let id = backend.put_chunk(b"jello").unwrap();
+        assert_eq!(backend.get_chunk(id).unwrap(), b"jello");
+        assert_eq!(backend.inner.uploaded_bytes(), 10);
+
+        assert_eq!(zero_chunk(&[]), None);
+    }
+
+    #[test]
+    fn holes_in_files_read_back_as_zeros() {
+        let mut fs = FS::new(SparseBackend::new(TestBackend::default()));
+        fs.chunk_size = crate::MIN_CHUNK_SIZE;
+
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new("sparse"), 0o644, 0)
+            .unwrap()
+            .ino;
+        let tail = 2 * crate::MIN_CHUNK_SIZE;
+        fs.do_write(ino, tail as i64, b"end").unwrap();
+        fs.do_release(ino, 0).unwrap();
+
+        let puts: Vec<Call> = fs.backend.inner.calls();
+        let puts = puts.iter().filter(|call| matches!(call, Call::Put { .. }));
+        assert_eq!(puts.count(), 1);
+
+        fs.data_table.clear();
+        let data = fs.do_read(0, ino, 0, tail as u32 + 3).unwrap();
+        assert!(data[..tail].iter().all(|&byte| byte == 0));
+        assert_eq!(&data[tail..], b"end");
+    }
+}
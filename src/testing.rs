@@ -0,0 +1,189 @@
+// Test support: an in-memory backend that records every call and can be made
+// slow or unreliable, and a way to mount an FS in the background
+
+use crate::backend::{ChunkId, MemBackend, StorageBackend};
+use crate::FS;
+use fuser::{BackgroundSession, Filesystem, MountOption};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    Put { id: ChunkId, len: usize },
+    Get { id: ChunkId },
+    GetRange { id: ChunkId, range: Range<usize> },
+    Delete { id: ChunkId },
+    List,
+    LoadIndex,
+    SaveIndex { len: usize },
+}
+
+#[derive(Default)]
+pub struct TestBackend {
+    inner: MemBackend,
+    calls: Mutex<Vec<Call>>,
+    latency: Mutex<Duration>,
+    failures: Mutex<VecDeque<ErrorKind>>,
+}
+
+impl TestBackend {
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn uploaded_bytes(&self) -> usize {
+        self.calls()
+            .iter()
+            .map(|call| match call {
+                Call::Put { len, .. } => *len,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    // Every call sleeps this long before doing anything
+    pub fn set_latency(&self, latency: Duration) {
+        *self.latency.lock().unwrap() = latency;
+    }
+
+    // The next call fails with `kind`, queued failures are used up in order
+    pub fn fail_next(&self, kind: ErrorKind) {
+        self.failures.lock().unwrap().push_back(kind);
+    }
+
+    // Drops a chunk behind the filesystem's back, as if it was lost remotely
+    pub fn lose_chunk(&self, id: ChunkId) {
+        let _ = self.inner.delete_chunk(id);
+    }
+
+    // Swaps a chunk's contents behind the filesystem's back, as if it went bad remotely
+    pub fn corrupt_chunk(&self, id: ChunkId, data: &[u8]) {
+        self.inner.overwrite_chunk(id, data);
+    }
+
+    // Copied out first, so calls on other threads don't queue up behind the lock
+    fn delay(&self) {
+        let latency = *self.latency.lock().unwrap();
+        thread::sleep(latency);
+    }
+
+    fn call<T>(&self, call: Call, op: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        self.delay();
+
+        if let Some(kind) = self.failures.lock().unwrap().pop_front() {
+            return Err(io::Error::new(
+                kind,
+                format!("injected failure of {:?}", call),
+            ));
+        }
+
+        self.calls.lock().unwrap().push(call);
+
+        op()
+    }
+}
+
+impl StorageBackend for TestBackend {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        // The id is only known afterwards, so the call is logged by hand
+        self.delay();
+
+        if let Some(kind) = self.failures.lock().unwrap().pop_front() {
+            return Err(io::Error::new(kind, "injected failure of a put"));
+        }
+
+        let id = self.inner.put_chunk(data)?;
+        self.calls.lock().unwrap().push(Call::Put {
+            id,
+            len: data.len(),
+        });
+
+        Ok(id)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        self.call(Call::Get { id }, || self.inner.get_chunk(id))
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        self.call(Call::Delete { id }, || self.inner.delete_chunk(id))
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        self.call(Call::LoadIndex, || self.inner.load_index())
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        self.call(Call::SaveIndex { len: index.len() }, || {
+            self.inner.save_index(index)
+        })
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        self.call(Call::SaveIndex { len: index.len() }, || {
+            self.inner.save_index_if(expected, index)
+        })
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        self.call(Call::List, || self.inner.list_chunks())
+    }
+
+    fn store_id(&self) -> Option<String> {
+        self.inner.store_id()
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let call = Call::GetRange {
+            id,
+            range: range.clone(),
+        };
+
+        self.call(call, || self.inner.get_chunk_range(id, range))
+    }
+}
+
+impl FS<TestBackend> {
+    // An FS whose handlers can be called through the do_* methods, no mount needed
+    pub fn new_for_test() -> Self {
+        FS::new(TestBackend::default())
+    }
+
+    pub fn backend(&self) -> &TestBackend {
+        &self.backend
+    }
+}
+
+static TEMP_DIRS: AtomicU64 = AtomicU64::new(0);
+
+// A fresh, empty directory under the system temp dir
+pub fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "discordfs-test-{}-{}",
+        process::id(),
+        TEMP_DIRS.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+// Mounts `fs` on a fresh directory under the system temp dir. It's unmounted
+// again when the returned session is dropped. A SharedFS can be mounted twice.
+pub fn mount<F: Filesystem + Send + 'static>(fs: F) -> io::Result<(BackgroundSession, PathBuf)> {
+    let mountpoint = temp_dir();
+
+    let options = [MountOption::FSName("discordfs".to_string())];
+    let session = fuser::spawn_mount2(fs, &mountpoint, &options)?;
+
+    Ok((session, mountpoint))
+}
@@ -0,0 +1,1887 @@
+use super::*;
+use backend::{DirBackend, MemBackend};
+use std::io::ErrorKind;
+use std::os::unix::thread::JoinHandleExt;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, UNIX_EPOCH};
+use testing::{Call, TestBackend};
+
+#[test]
+fn sigterm_persists_the_index() {
+    let path = testing::temp_dir().join("index.json");
+    let mut fs = FS {
+        index_path: Some(path.clone()),
+        ..FS::new_for_test()
+    };
+    fs.add_file("hello.txt", b"Hello, World!").unwrap();
+
+    // Stands in for the session: unmounting makes it call `destroy`.
+    let fs = Arc::new(Mutex::new(fs));
+    let session = fs.clone();
+    let signals = block_shutdown_signals();
+    let handler = spawn_shutdown_handler(signals, move || session.lock().unwrap().destroy());
+
+    unsafe { libc::pthread_kill(handler.as_pthread_t(), libc::SIGTERM) };
+    handler.join().unwrap();
+
+    // Dirty data was uploaded before the index went out
+    let fs = fs.lock().unwrap();
+    let index = Index::load(&path).unwrap();
+    let chunks = &index.chunk_table[&2];
+    assert!(fs.dirty.is_empty());
+    assert_eq!(index.path_table[&2], "hello.txt");
+    assert_eq!(fs.backend.get_chunk(chunks[0]).unwrap(), b"Hello, World!");
+}
+
+#[test]
+fn total_size_matches_a_full_recompute() {
+    let mut fs = FS::new_for_test();
+
+    for i in 0..1000 {
+        fs.add_file(&format!("file{}.txt", i), &vec![0; i % 37])
+            .unwrap();
+    }
+
+    assert_eq!(fs.total_size, fs.compute_fs_size());
+}
+
+#[test]
+fn open_and_close_leave_the_size_alone() {
+    let mut fs = FS::new_for_test();
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+    let updates = fs.size_updates;
+
+    let fh = fs.do_open(Caller::mounter(), ino, libc::O_RDONLY).unwrap();
+    fs.do_flush(ino).unwrap();
+    fs.do_release(ino, fh).unwrap();
+    assert_eq!(fs.size_updates, updates);
+
+    fs.add_file("other.txt", b"more").unwrap();
+    assert_eq!(fs.size_updates, updates + 1);
+}
+
+#[test]
+fn read_only_mounts_refuse_writes_but_serve_reads() {
+    let mut fs = FS {
+        read_only: true,
+        ..FS::new_for_test()
+    };
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+
+    assert_eq!(fs.do_write(ino, 0, b"Bye"), Err(EROFS));
+    assert_eq!(
+        fs.do_create(Caller::mounter(), OsStr::new("new.txt"), 0o644, 0)
+            .unwrap_err(),
+        EROFS
+    );
+    assert_eq!(fs.do_read(0, ino, 0, 13), Ok(b"Hello, World!".to_vec()));
+}
+
+#[test]
+fn open_resolves_files_and_directories() {
+    let mut fs = FS::new_for_test();
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+
+    assert!(fs.do_open(Caller::mounter(), ino, libc::O_RDWR).is_ok());
+    assert!(fs.do_open(Caller::mounter(), 1, libc::O_RDONLY).is_ok());
+    assert_eq!(
+        fs.do_open(Caller::mounter(), 1, libc::O_WRONLY),
+        Err(EISDIR)
+    );
+    assert_eq!(
+        fs.do_open(Caller::mounter(), 42, libc::O_RDONLY),
+        Err(ENOENT)
+    );
+}
+
+#[test]
+fn writes_near_the_offset_limit_fail_cleanly() {
+    let mut fs = FS::new_for_test();
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+
+    assert_eq!(fs.do_write(ino, i64::MAX - 1, b"Bye"), Err(EFBIG));
+    assert_eq!(fs.do_write(ino, -1, b"Bye"), Err(EINVAL));
+    assert_eq!(fs.do_read(0, ino, 0, 13), Ok(b"Hello, World!".to_vec()));
+}
+
+#[test]
+fn forget_drops_unlinked_inodes() {
+    let mut fs = FS::new_for_test();
+
+    for i in 0..100 {
+        let name = format!("file{}.txt", i);
+        fs.do_create(Caller::mounter(), OsStr::new(&name), 0o644, 0)
+            .unwrap();
+        fs.do_lookup(1, OsStr::new(&name)).unwrap();
+    }
+
+    for i in 0..50 {
+        fs.do_unlink(OsStr::new(&format!("file{}.txt", i))).unwrap();
+    }
+
+    // Still referenced by the kernel, so the data stays around
+    assert_eq!(fs.data_table.len(), 100);
+
+    for ino in 2..102 {
+        fs.do_forget(ino, 2);
+    }
+
+    assert_eq!(fs.data_table.len(), 50);
+    assert!(fs.lookup_counts.is_empty());
+}
+
+#[test]
+fn open_handles_keep_working_after_an_unlink() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("tmp.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    let fh = fs.open_handle(ino);
+    fs.do_write(ino, 0, b"abc").unwrap();
+
+    fs.do_unlink(OsStr::new("tmp.txt")).unwrap();
+    assert_eq!(fs.do_lookup(1, OsStr::new("tmp.txt")), Err(ENOENT));
+
+    // A new file under the same name is a different one
+    fs.add_file("tmp.txt", b"other").unwrap();
+
+    assert_eq!(fs.do_write(ino, 3, b"def"), Ok(3));
+    assert_eq!(fs.do_getattr(ino).unwrap().size, 6);
+    fs.do_setattr(
+        ino,
+        AttrChanges {
+            size: Some(5),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(fs.do_read(fh, ino, 0, 10), Ok(b"abcde".to_vec()));
+    assert_eq!(fs.total_size, 10);
+
+    fs.do_release(ino, fh).unwrap();
+    fs.do_forget(ino, 1);
+    assert_eq!(fs.do_getattr(ino), Err(ENOENT));
+    assert_eq!(fs.total_size, 5);
+
+    let other = fs.do_lookup(1, OsStr::new("tmp.txt")).unwrap().ino;
+    assert_eq!(fs.do_read(0, other, 0, 10), Ok(b"other".to_vec()));
+}
+
+#[test]
+fn negative_offsets_are_rejected() {
+    let mut fs = FS::new_for_test();
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+
+    assert_eq!(fs.do_read(0, ino, -1, 4), Err(EINVAL));
+    assert_eq!(fs.do_write(ino, -1, b"Bye"), Err(EINVAL));
+    assert_eq!(fs.do_read(0, ino, 100, 4), Ok(b"".to_vec()));
+}
+
+#[test]
+fn reads_return_exactly_the_requested_window() {
+    let mut fs = FS::new_for_test();
+    let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    let (ino, _) = fs.add_file("pattern.bin", &data).unwrap();
+
+    assert_eq!(fs.do_read(0, ino, 450, 100), Ok(data[450..550].to_vec()));
+    assert_eq!(fs.do_read(0, ino, 0, 1), Ok(data[..1].to_vec()));
+    assert_eq!(fs.do_read(0, ino, 950, 100), Ok(data[950..].to_vec()));
+    assert_eq!(fs.do_read(0, ino, 1000, 100), Ok(b"".to_vec()));
+}
+
+#[test]
+fn threads_spawned_after_blocking_leave_shutdown_signals_to_the_handler() {
+    block_shutdown_signals();
+
+    // Like the metrics server or a checkpoint thread, started before the handler
+    let blocked = thread::spawn(|| {
+        let mut mask: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, ptr::null(), &mut mask) };
+
+        [libc::SIGINT, libc::SIGTERM].map(|signal| unsafe { libc::sigismember(&mask, signal) })
+    });
+
+    assert_eq!(blocked.join().unwrap(), [1, 1]);
+}
+
+fn round_trip<B: StorageBackend>(backend: B) -> B {
+    let data: Vec<u8> = (0..DEFAULT_CHUNK_SIZE + 1000)
+        .map(|i| (i % 251) as u8)
+        .collect();
+
+    let mut fs = FS::new(backend);
+    let attr = fs
+        .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+        .unwrap();
+    fs.do_write(attr.ino, 0, &data).unwrap();
+    fs.do_release(attr.ino, 0).unwrap();
+    assert_eq!(fs.chunk_table[&attr.ino].len(), 2);
+
+    // A fresh FS over the same store only has the index to go on
+    let mut fs = FS::new(fs.backend);
+    fs.restore_index(Index::fetch(&fs.backend, None).unwrap().unwrap());
+    assert_eq!(
+        fs.do_read(0, attr.ino, 0, data.len() as u32),
+        Ok(data[..].to_vec())
+    );
+
+    fs.backend
+}
+
+#[test]
+fn files_round_trip_through_the_memory_backend() {
+    round_trip(MemBackend::default());
+}
+
+#[test]
+fn files_round_trip_through_the_directory_backend() {
+    let dir = testing::temp_dir();
+    round_trip(DirBackend::open(dir.clone()).unwrap());
+
+    // Only the two chunks of the file are stored
+    assert_eq!(std::fs::read_dir(dir.join("chunks")).unwrap().count(), 2);
+}
+
+#[test]
+fn processes_sharing_a_store_never_take_the_same_chunk_id() {
+    let dir = testing::temp_dir();
+    let one = DirBackend::open(dir.clone()).unwrap();
+    let other = DirBackend::open(dir.clone()).unwrap();
+
+    // Both start counting from the same highest id
+    let first = one.put_chunk(b"one").unwrap();
+    let second = other.put_chunk(b"other").unwrap();
+
+    assert_ne!(first, second);
+    assert_eq!(one.get_chunk(first).unwrap(), b"one");
+    assert_eq!(one.get_chunk(second).unwrap(), b"other");
+
+    // They're one store, which a new store in another place is not
+    assert_eq!(one.store_id(), other.store_id());
+    let elsewhere = DirBackend::open(testing::temp_dir()).unwrap();
+    assert_ne!(one.store_id(), elsewhere.store_id());
+}
+
+#[test]
+fn the_newer_of_the_local_and_the_shared_index_is_used() {
+    let path = testing::temp_dir().join("index.json");
+    let mut fs = FS {
+        index_path: Some(path.clone()),
+        ..FS::new_for_test()
+    };
+    fs.add_file("local.txt", b"local").unwrap();
+    fs.save_index();
+
+    // Another mount saved since, which only reaches the backend
+    let mut shared = Index::fetch(fs.backend(), None).unwrap().unwrap();
+    shared.lookup_table.remove("local.txt");
+    shared.write(fs.backend(), None).unwrap();
+
+    let index = Index::fetch(fs.backend(), Some(&path)).unwrap().unwrap();
+    assert_eq!(index.version, shared.version);
+    assert!(!index.lookup_table.contains_key("local.txt"));
+
+    // Ours is newer once the backend missed a save, or when it can't be read
+    fs.save_index();
+    fs.add_file("later.txt", b"later").unwrap();
+    fs.backend().fail_next(ErrorKind::Other);
+    fs.save_index();
+    let index = Index::fetch(fs.backend(), Some(&path)).unwrap().unwrap();
+    assert!(index.lookup_table.contains_key("later.txt"));
+
+    fs.backend().fail_next(ErrorKind::Other);
+    let index = Index::fetch(fs.backend(), Some(&path)).unwrap().unwrap();
+    assert!(index.lookup_table.contains_key("later.txt"));
+}
+
+#[test]
+fn a_crash_between_write_and_swap_keeps_an_index() {
+    let dir = testing::temp_dir();
+    let backend = DirBackend::open(dir.clone()).unwrap();
+
+    let mut fs = FS::new(backend);
+    fs.add_file("old.txt", b"old").unwrap();
+    fs.save_index();
+    fs.add_file("new.txt", b"new").unwrap();
+    fs.save_index();
+
+    // The next save dies after writing the temporary file...
+    std::fs::write(dir.join("index.json.tmp"), b"{\"lookup_table\":").unwrap();
+    let index = Index::fetch(&fs.backend, None).unwrap().unwrap();
+    assert!(index.lookup_table.contains_key("new.txt"));
+
+    // ...or the current generation is damaged, so the previous one is used
+    std::fs::write(dir.join("index.json"), b"{\"lookup_table\":").unwrap();
+    let index = Index::fetch(&fs.backend, None).unwrap().unwrap();
+    assert!(index.lookup_table.contains_key("old.txt"));
+    assert!(!index.lookup_table.contains_key("new.txt"));
+}
+
+#[test]
+fn forgotten_inodes_are_reused() {
+    let mut fs = FS::new_for_test();
+
+    let first = fs
+        .do_create(Caller::mounter(), OsStr::new("first.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_unlink(OsStr::new("first.txt")).unwrap();
+
+    // The kernel still holds a reference, so the number can't come back yet
+    let second = fs
+        .do_create(Caller::mounter(), OsStr::new("second.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    assert_ne!(second, first);
+
+    fs.do_forget(first, 1);
+    let third = fs
+        .do_create(Caller::mounter(), OsStr::new("third.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    assert_eq!(third, first);
+    assert_eq!(fs.last_inode, second);
+}
+
+#[test]
+fn backend_failures_reach_the_kernel_as_matching_errnos() {
+    let classes = [
+        (ErrorKind::TimedOut, libc::EIO),
+        (ErrorKind::NotFound, ENOENT),
+        (ErrorKind::PermissionDenied, libc::EACCES),
+        (ErrorKind::FileTooLarge, EFBIG),
+        (ErrorKind::QuotaExceeded, libc::EDQUOT),
+        (ErrorKind::StorageFull, libc::ENOSPC),
+    ];
+
+    for (kind, errno) in classes {
+        let mut fs = FS::new_for_test();
+        let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+        fs.backend.fail_next(kind);
+        assert_eq!(fs.do_flush(ino), Err(errno), "{:?}", kind);
+
+        // Once stored, the next read has to fetch it
+        fs.do_flush(ino).unwrap();
+        fs.data_table.clear();
+        fs.backend.fail_next(kind);
+        let errno = if kind == ErrorKind::NotFound {
+            libc::EIO
+        } else {
+            errno
+        };
+        assert_eq!(fs.do_read(0, ino, 0, 13), Err(errno), "{:?}", kind);
+    }
+}
+
+#[test]
+fn writes_past_the_capacity_fail_with_enospc() {
+    let mut fs = FS::new_for_test();
+    fs.capacity = Some(100);
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("full.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    assert_eq!(fs.do_write(ino, 0, &[1; 100]), Ok(100));
+    assert_eq!(fs.do_write(ino, 100, &[2]), Err(libc::ENOSPC));
+    assert_eq!(fs.do_fallocate(ino, 0, 101, 0), Err(libc::ENOSPC));
+
+    // Rewriting within the file doesn't grow it, and reads still work
+    assert_eq!(fs.do_write(ino, 50, &[3; 50]), Ok(50));
+    assert_eq!(
+        fs.do_read(0, ino, 40, 20).unwrap(),
+        [[1; 10], [3; 10]].concat()
+    );
+
+    // Truncating frees the space up again
+    assert_eq!(
+        fs.do_setattr(
+            ino,
+            AttrChanges {
+                size: Some(40),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .size,
+        40
+    );
+    assert_eq!(fs.do_write(ino, 40, &[4; 60]), Ok(60));
+    assert_eq!(fs.total_size, 100);
+}
+
+#[test]
+fn fallocate_rejects_negative_offsets() {
+    let mut fs = FS::new_for_test();
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+
+    assert_eq!(fs.do_fallocate(ino, -1, 10, 0), Err(EINVAL));
+    assert_eq!(fs.do_fallocate(ino, 0, -10, 0), Err(EINVAL));
+    assert_eq!(fs.do_fallocate(ino, 0, 100, 0), Ok(()));
+    assert_eq!(fs.get_attr(ino).unwrap().size, 100);
+}
+
+#[test]
+fn reads_of_lost_chunks_fail_with_eio() {
+    let mut fs = FS::new_for_test();
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+    fs.do_release(ino, 0).unwrap();
+
+    let chunks = fs.chunk_table[&ino].clone();
+    fs.backend.lose_chunk(chunks[0]);
+    assert_eq!(fs.do_read(0, ino, 0, 13), Err(libc::EIO));
+
+    // Dirty data that is no longer buffered can't be served from older chunks
+    let (ino, _) = fs.add_file("dirty.txt", b"unsaved").unwrap();
+    fs.data_table.remove(&ino);
+    assert_eq!(fs.do_read(0, ino, 0, 7), Err(libc::EIO));
+}
+
+#[test]
+fn metrics_follow_the_traffic() {
+    let metrics = Arc::new(Metrics::default());
+    let mut fs = FS::new(MetricsBackend::new(MemBackend::default(), metrics.clone()));
+    fs.metrics = metrics.clone();
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("hello.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, b"Hello, World!").unwrap();
+    assert!(metrics.render().contains("\ndiscordfs_dirty_bytes 13\n"));
+
+    fs.do_release(ino, 0).unwrap();
+    fs.do_read(0, ino, 0, 13).unwrap();
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("\ndiscordfs_uploads_total 1\n"));
+    assert!(rendered.contains("\ndiscordfs_upload_bytes_total 13\n"));
+    assert!(rendered.contains("\ndiscordfs_downloads_total 1\n"));
+    assert!(rendered.contains("\ndiscordfs_download_bytes_total 13\n"));
+    assert!(rendered.contains("\ndiscordfs_cache_misses_total 1\n"));
+    assert!(rendered.contains("\ndiscordfs_dirty_bytes 0\n"));
+}
+
+#[test]
+fn concurrent_uploads_report_every_chunk_once() {
+    let mut fs = FS::new_for_test();
+    fs.concurrency = 3;
+    fs.chunk_size = MIN_CHUNK_SIZE;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = events.clone();
+    fs.progress = Some(Arc::new(move |progress: &Progress| {
+        seen.lock().unwrap().push(*progress);
+    }));
+
+    let (ino, _) = fs
+        .add_file("big.bin", &vec![1; 2 * MIN_CHUNK_SIZE + 100])
+        .unwrap();
+    fs.do_flush(ino).unwrap();
+
+    // The first event is the start, before anything was uploaded
+    let events = events.lock().unwrap();
+    let (start, chunks) = events.split_first().unwrap();
+    assert_eq!((start.chunks, start.bytes), (0, 0));
+    assert_eq!(chunks.len(), 3);
+
+    let mut done = 0;
+    for (i, progress) in chunks.iter().enumerate() {
+        assert_eq!(progress.chunks, i + 1);
+        assert_eq!(progress.total_chunks, 3);
+        assert!(progress.bytes > done);
+        done = progress.bytes;
+    }
+
+    assert_eq!(done, 2 * MIN_CHUNK_SIZE as u64 + 100);
+    assert_eq!(done, start.total_bytes);
+}
+
+fn upload_time(concurrency: usize) -> Duration {
+    let mut fs = FS::new_for_test();
+    fs.backend.set_latency(Duration::from_millis(100));
+    fs.concurrency = concurrency;
+    fs.chunk_size = MIN_CHUNK_SIZE;
+
+    let data: Vec<u8> = (0..8 * MIN_CHUNK_SIZE)
+        .map(|i| (i / MIN_CHUNK_SIZE) as u8)
+        .collect();
+    let (ino, _) = fs.add_file("big.bin", &data).unwrap();
+
+    let start = Instant::now();
+    fs.do_flush(ino).unwrap();
+    let elapsed = start.elapsed();
+    fs.backend.set_latency(Duration::ZERO);
+
+    // Chunks stay in file order however the uploads finished
+    let chunks = &fs.chunk_table[&ino];
+    for (i, id) in chunks.iter().enumerate() {
+        assert_eq!(fs.backend.get_chunk(*id).unwrap()[0], i as u8);
+    }
+
+    elapsed
+}
+
+#[test]
+fn chunks_upload_in_parallel() {
+    let serial = upload_time(1);
+    let parallel = upload_time(4);
+
+    assert!(serial >= Duration::from_millis(800));
+    assert!(parallel < serial * 6 / 10, "{:?} vs {:?}", parallel, serial);
+}
+
+#[test]
+fn writes_advance_mtime_and_reads_advance_atime() {
+    let mut fs = FS::new_for_test();
+    let attr = fs
+        .do_create(Caller::mounter(), OsStr::new("file"), 0o644, 0)
+        .unwrap();
+    assert_eq!(attr.crtime, attr.mtime);
+    assert_ne!(attr.crtime, UNIX_EPOCH);
+
+    thread::sleep(Duration::from_millis(10));
+    fs.do_write(attr.ino, 0, b"data").unwrap();
+    let written = *fs.get_attr(attr.ino).unwrap();
+    assert!(written.mtime > written.crtime);
+    assert_eq!(written.ctime, written.mtime);
+    assert_eq!(written.atime, attr.atime);
+
+    fs.do_read(0, attr.ino, 0, 4).unwrap();
+    let read = *fs.get_attr(attr.ino).unwrap();
+    assert!(read.atime > written.mtime);
+    assert_eq!(read.mtime, written.mtime);
+
+    // Only one atime update until the file changes again
+    fs.do_read(0, attr.ino, 0, 4).unwrap();
+    assert_eq!(fs.get_attr(attr.ino).unwrap().atime, read.atime);
+}
+
+#[test]
+fn rewrites_leave_the_saved_chunks_alone_until_the_next_save() {
+    let dir = testing::temp_dir();
+    let mut fs = FS::new(DirBackend::open(dir.clone()).unwrap());
+    fs.checkpoint_interval = Some(Duration::from_secs(3600));
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("small.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, b"first").unwrap();
+    fs.flush_data(ino).unwrap();
+    fs.checkpoint();
+    let id = fs.chunk_table[&ino][0];
+
+    // Until the rewrite is saved, the saved index still reads back what it recorded
+    fs.do_write(ino, 0, b"second").unwrap();
+    fs.flush_data(ino).unwrap();
+    assert_ne!(fs.chunk_table[&ino], vec![id]);
+    assert_eq!(fs.backend.get_chunk(id).unwrap(), b"first");
+
+    fs.checkpoint();
+    let saved = Index::fetch(&fs.backend, None).unwrap().unwrap();
+    assert_eq!(
+        fs.backend.get_chunk(saved.chunk_table[&ino][0]).unwrap(),
+        b"second"
+    );
+    assert!(fs.backend.get_chunk(id).is_err());
+    assert_eq!(std::fs::read_dir(dir.join("chunks")).unwrap().count(), 1);
+}
+
+fn atime_after_two_reads(policy: AtimePolicy) -> (SystemTime, SystemTime, SystemTime) {
+    let mut fs = FS {
+        atime: policy,
+        ..FS::new_for_test()
+    };
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("file"), 0o644, 0)
+        .unwrap()
+        .ino;
+    let created = fs.get_attr(ino).unwrap().atime;
+
+    thread::sleep(Duration::from_millis(10));
+    fs.do_read(0, ino, 0, 1).unwrap();
+    let first = fs.get_attr(ino).unwrap().atime;
+
+    thread::sleep(Duration::from_millis(10));
+    fs.do_read(0, ino, 0, 1).unwrap();
+    let second = fs.get_attr(ino).unwrap().atime;
+
+    (created, first, second)
+}
+
+#[test]
+fn the_atime_policy_decides_which_reads_count() {
+    let (created, first, second) = atime_after_two_reads(AtimePolicy::Noatime);
+    assert!(created == first && first == second);
+
+    let (created, first, second) = atime_after_two_reads(AtimePolicy::Relatime);
+    assert!(created < first && first == second);
+
+    let (created, first, second) = atime_after_two_reads(AtimePolicy::Strict);
+    assert!(created < first && first < second);
+}
+
+#[test]
+fn no_new_files_once_the_quota_is_reached() {
+    let mut fs = FS::new_for_test();
+    fs.capacity = Some(100);
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("full.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, &[1; 100]).unwrap();
+    assert_eq!(
+        fs.do_create(Caller::mounter(), OsStr::new("more.txt"), 0o644, 0),
+        Err(libc::ENOSPC)
+    );
+    assert_eq!(fs.do_write(ino, 100, &[1]), Err(libc::ENOSPC));
+
+    // Truncating back below the quota makes room again
+    fs.do_setattr(
+        ino,
+        AttrChanges {
+            size: Some(99),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert!(fs
+        .do_create(Caller::mounter(), OsStr::new("more.txt"), 0o644, 0)
+        .is_ok());
+}
+
+#[test]
+fn metadata_changes_survive_a_remount() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("file"), 0o644, 0)
+        .unwrap()
+        .ino;
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+    let changes = AttrChanges {
+        mode: Some(0o100640),
+        uid: Some(1000),
+        gid: Some(100),
+        mtime: Some(TimeOrNow::SpecificTime(mtime)),
+        ..Default::default()
+    };
+    let attr = fs.do_setattr(ino, changes).unwrap();
+    assert_eq!(attr.perm, 0o640);
+    assert!(attr.ctime > mtime);
+
+    // Nothing but the setattr itself saved the index
+    let mut fs = FS::new(fs.backend);
+    fs.restore_index(Index::fetch(&fs.backend, None).unwrap().unwrap());
+    let attr = fs.get_attr(ino).unwrap();
+    assert_eq!((attr.perm, attr.uid, attr.gid), (0o640, 1000, 100));
+    assert_eq!(attr.mtime, mtime);
+}
+
+#[test]
+fn read_only_mounts_refuse_metadata_changes() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("file"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.read_only = true;
+
+    let changes = AttrChanges {
+        mode: Some(0o600),
+        ..Default::default()
+    };
+    assert_eq!(fs.do_setattr(ino, changes), Err(libc::EROFS));
+    assert_eq!(fs.do_setattr(ino, AttrChanges::default()).unwrap().ino, ino);
+}
+
+#[test]
+fn seeding_keeps_mode_owner_and_mtime() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = testing::temp_dir();
+    let path = dir.join("notes.txt");
+    std::fs::write(&path, b"seeded").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    std::fs::create_dir(dir.join("nested")).unwrap();
+
+    let mut fs = FS::new_for_test();
+    fs.import_dir(&dir).unwrap();
+    assert!(fs.do_lookup(1, OsStr::new("nested")).is_err());
+
+    let metadata = std::fs::metadata(&path).unwrap();
+    let attr = fs.do_lookup(1, OsStr::new("notes.txt")).unwrap();
+    assert_eq!(attr.perm, 0o640);
+    assert_eq!((attr.uid, attr.gid), (metadata.uid(), metadata.gid()));
+    assert_eq!(attr.mtime, mtime);
+    assert_eq!(fs.do_read(0, attr.ino, 0, 100), Ok(b"seeded".to_vec()));
+}
+
+#[test]
+fn flushes_only_send_what_changed() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    let data = vec![1; 2 * MIN_CHUNK_SIZE + 10];
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, &data).unwrap();
+    fs.do_flush(ino).unwrap();
+    assert_eq!(fs.backend.uploaded_bytes(), data.len());
+
+    // A clean file doesn't touch the backend at all
+    let calls = fs.backend.calls().len();
+    fs.do_flush(ino).unwrap();
+    assert_eq!(fs.backend.calls().len(), calls);
+
+    // A rewritten single-chunk file goes up anew, and only then is the old one deleted
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("small.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, b"first").unwrap();
+    fs.do_flush(ino).unwrap();
+    let id = fs.chunk_table[&ino][0];
+
+    fs.do_write(ino, 0, b"second").unwrap();
+    fs.do_flush(ino).unwrap();
+    let calls = fs.backend.calls();
+    let put = calls
+        .iter()
+        .rposition(|call| matches!(call, Call::Put { len: 6, .. }));
+    let deleted = calls.iter().position(|call| *call == Call::Delete { id });
+    assert!(put.unwrap() < deleted.unwrap());
+    assert_eq!(fs.backend.uploaded_bytes(), data.len() + 5 + 6);
+}
+
+#[test]
+#[ignore = "needs FUSE and permission to mount"]
+fn files_written_through_a_mount_read_back() {
+    let (session, mountpoint) = testing::mount(FS::new_for_test()).unwrap();
+
+    std::fs::write(mountpoint.join("hello.txt"), b"Hello, World!").unwrap();
+    assert_eq!(
+        std::fs::read(mountpoint.join("hello.txt")).unwrap(),
+        b"Hello, World!"
+    );
+
+    drop(session);
+}
+
+#[test]
+fn the_root_lists_itself_only_as_dot_entries() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("file.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+
+    let mut entries = fs.do_readdir(1).unwrap();
+    entries.sort_by_key(|entry| entry.2.clone());
+    assert_eq!(
+        entries,
+        vec![
+            (1, FileType::Directory, ".".into()),
+            (1, FileType::Directory, "..".into()),
+            (ino, FileType::RegularFile, "file.txt".into()),
+        ]
+    );
+
+    assert_eq!(fs.do_readdir(ino), Err(ENOTDIR));
+}
+
+#[test]
+fn paged_listings_return_every_entry_once() {
+    let mut fs = FS::new_for_test();
+    fs.save_index();
+
+    // Loaded as a saved tree, adding them one by one is slow in a debug build
+    let mut index = Index::fetch(fs.backend(), None).unwrap().unwrap();
+    for ino in 2..5002 {
+        let name = format!("file{}", ino - 2);
+        let attr = FileAttr {
+            ino,
+            kind: FileType::RegularFile,
+            ..root_attr(0o644)
+        };
+
+        index.lookup_table.insert(name.clone(), attr);
+        index.path_table.insert(ino, name);
+        index.chunk_table.insert(ino, Vec::new());
+    }
+    index.last_inode = 5001;
+    fs.restore_index(index);
+
+    let fh = fs.open_handle(ROOT_INO);
+    let mut names = Vec::new();
+    let mut offset = 0;
+
+    // A reply with room for 7 entries, and the directory changing between calls
+    loop {
+        let mut page = Vec::new();
+        fs.do_readdir_from(fh, ROOT_INO, offset, |_, cookie, _, name| {
+            page.push((cookie, name.to_owned()));
+            page.len() == 7
+        })
+        .unwrap();
+
+        let Some(&(cookie, _)) = page.last() else {
+            break;
+        };
+
+        assert!(cookie > offset);
+        offset = cookie;
+        names.extend(page.into_iter().map(|(_, name)| name));
+
+        if names.len() % 700 == 0 {
+            fs.add_file(&format!("new{}", names.len()), b"").unwrap();
+            fs.do_unlink(OsStr::new(&format!("file{}", names.len())))
+                .unwrap();
+        }
+    }
+
+    let unique: HashSet<&OsString> = names.iter().collect();
+    assert_eq!(names.len(), 5002);
+    assert_eq!(unique.len(), 5002);
+
+    // Starting over takes in the changes
+    let mut relisted = 0;
+    fs.do_readdir_from(fh, ROOT_INO, 0, |_, _, _, _| {
+        relisted += 1;
+        false
+    })
+    .unwrap();
+    assert_eq!(relisted, 5002);
+    assert!(fs.do_lookup(ROOT_INO, OsStr::new("new700")).is_ok());
+}
+
+#[test]
+fn created_files_read_back_what_was_written() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("notes.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    assert_eq!(fs.do_lookup(1, OsStr::new("notes.txt")).unwrap().ino, ino);
+
+    assert_eq!(fs.do_write(ino, 0, b"Hello"), Ok(5));
+    assert_eq!(fs.do_write(ino, 5, b", World!"), Ok(8));
+    assert_eq!(fs.do_read(0, ino, 0, 100), Ok(b"Hello, World!".to_vec()));
+    assert_eq!(fs.do_lookup(1, OsStr::new("notes.txt")).unwrap().size, 13);
+
+    // Once it's only in the backend, a read has to fetch it back
+    fs.do_release(ino, 0).unwrap();
+    assert_eq!(fs.backend().uploaded_bytes(), 13);
+    fs.data_table.clear();
+    assert_eq!(fs.do_read(0, ino, 0, 100), Ok(b"Hello, World!".to_vec()));
+
+    let id = fs.chunk_table[&ino][0];
+    assert!(fs.backend().calls().contains(&Call::Get { id }));
+}
+
+#[test]
+fn mount_options_add_to_the_defaults() {
+    let extra = parse_mount_options("ro,default_permissions").unwrap();
+    assert_eq!(
+        extra,
+        vec![MountOption::RO, MountOption::DefaultPermissions]
+    );
+
+    let options = mount_options(true, extra);
+    assert_eq!(options[0], MountOption::RO);
+    assert!(!options.contains(&MountOption::RW));
+    assert!(options.contains(&MountOption::DefaultPermissions));
+
+    let options = mount_options(
+        false,
+        parse_mount_options("allow_root,fsname=mine").unwrap(),
+    );
+    assert_eq!(options[0], MountOption::RW);
+    assert!(options.contains(&MountOption::FSName("mine".to_string())));
+    assert!(!options.contains(&MountOption::FSName("discordfs".to_string())));
+    assert!(!options.contains(&MountOption::AllowOther));
+
+    assert!(parse_mount_options("ro,bogus").is_err());
+    assert!(parse_mount_options("uid=0").is_err());
+}
+
+#[test]
+fn sizes_take_binary_suffixes() {
+    assert_eq!(parse_size("512"), Ok(512));
+    assert_eq!(parse_size("64K"), Ok(64 << 10));
+    assert_eq!(parse_size("8M"), Ok(8 << 20));
+    assert_eq!(parse_size("8mib"), Ok(8 << 20));
+    assert_eq!(parse_size(" 1G "), Ok(1 << 30));
+
+    assert!(parse_size("8T").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("-1").is_err());
+    assert!(parse_size("99999999999999G").is_err());
+}
+
+#[test]
+fn reads_fetch_only_the_chunks_they_overlap() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    let data: Vec<u8> = (0..3 * MIN_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, &data).unwrap();
+    fs.do_release(ino, 0).unwrap();
+    fs.data_table.clear();
+    let chunks = fs.chunk_table[&ino].clone();
+
+    // Whole or in part
+    let fetches = |fs: &FS<TestBackend>| {
+        let mut ids = fs
+            .backend()
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Get { id } | Call::GetRange { id, .. } => Some(id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+
+    let middle = MIN_CHUNK_SIZE + 100;
+    assert_eq!(
+        fs.do_read(0, ino, middle as i64, 10),
+        Ok(data[middle..middle + 10].to_vec())
+    );
+    assert_eq!(fetches(&fs), vec![chunks[1]]);
+
+    // A window across a boundary fetches just the two chunks it overlaps
+    let boundary = 2 * MIN_CHUNK_SIZE - 5;
+    assert_eq!(
+        fs.do_read(0, ino, boundary as i64, 10),
+        Ok(data[boundary..boundary + 10].to_vec())
+    );
+    assert_eq!(fetches(&fs), vec![chunks[1], chunks[1], chunks[2]]);
+    assert!(!fs.data_table.contains_key(&ino));
+}
+
+#[test]
+fn open_files_keep_their_chunks_cached() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+
+    let create = |fs: &mut FS<TestBackend>, name: &str, data: &[u8]| {
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new(name), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, data).unwrap();
+        fs.do_release(ino, 0).unwrap();
+        ino
+    };
+
+    let data: Vec<u8> = (0..2 * MIN_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+    let open = create(&mut fs, "open.bin", &data);
+    let others: Vec<u64> = (0..2 * (CHUNK_CACHE_SIZE + fs.readahead))
+        .map(|i| {
+            create(
+                &mut fs,
+                &format!("other{}.bin", i),
+                &[i as u8; MIN_CHUNK_SIZE],
+            )
+        })
+        .collect();
+
+    let fh = fs.do_open(Caller::mounter(), open, libc::O_RDONLY).unwrap();
+    assert_eq!(fs.do_read(fh, open, 0, data.len() as u32), Ok(data.clone()));
+
+    for &ino in &others {
+        fs.do_read(0, ino, 0, MIN_CHUNK_SIZE as u32).unwrap();
+    }
+
+    let chunks = fs.chunk_table[&open].clone();
+    assert!(chunks.iter().all(|id| fs.chunk_cache.contains_key(id)));
+    assert!(fs.chunk_cache.len() <= CHUNK_CACHE_SIZE + fs.readahead);
+
+    let calls = fs.backend().calls().len();
+    assert_eq!(fs.do_read(fh, open, 0, data.len() as u32), Ok(data));
+    assert_eq!(fs.backend().calls().len(), calls);
+
+    // Once released, they age out like any other
+    fs.do_release(open, fh).unwrap();
+
+    for &ino in &others {
+        fs.do_read(0, ino, 0, MIN_CHUNK_SIZE as u32).unwrap();
+    }
+
+    assert!(!chunks.iter().any(|id| fs.chunk_cache.contains_key(id)));
+}
+
+#[test]
+fn small_reads_fetch_a_range_with_the_default_settings() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    let data: Vec<u8> = (0..2 * MIN_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, &data).unwrap();
+    fs.do_release(ino, 0).unwrap();
+    let chunks = fs.chunk_table[&ino].clone();
+    assert!(fs.verify && fs.checksums.contains_key(&chunks[1]));
+
+    let middle = MIN_CHUNK_SIZE + 100;
+    let read = |fs: &mut FS<TestBackend>| fs.do_read(0, ino, middle as i64, 10);
+
+    assert_eq!(read(&mut fs), Ok(data[middle..middle + 10].to_vec()));
+    assert_eq!(
+        fs.backend().calls().last(),
+        Some(&Call::GetRange {
+            id: chunks[1],
+            range: 100..110
+        })
+    );
+
+    // Part of a chunk isn't cached, the next read asks again
+    assert!(fs.chunk_cache.is_empty());
+    assert_eq!(read(&mut fs), Ok(data[middle..middle + 10].to_vec()));
+    let ranged = fs.backend().calls().into_iter();
+    assert_eq!(
+        ranged
+            .filter(|call| matches!(call, Call::GetRange { .. }))
+            .count(),
+        2
+    );
+
+    // Whole chunks are still checked against their checksum
+    fs.backend
+        .corrupt_chunk(chunks[1], &vec![0; MIN_CHUNK_SIZE]);
+    let whole = fs.do_read(0, ino, MIN_CHUNK_SIZE as i64, MIN_CHUNK_SIZE as u32);
+    assert_eq!(whole, Err(libc::EIO));
+}
+
+#[test]
+fn pending_chunks_count_only_what_the_next_flush_sends() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    let len = 3 * MIN_CHUNK_SIZE;
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, &vec![1; len]).unwrap();
+    assert_eq!(fs.pending_chunks(ino), 3);
+    assert_eq!(fs.flush_data(ino), Ok(3));
+    assert_eq!(fs.pending_chunks(ino), 0);
+
+    // A one-byte append only adds a chunk
+    fs.do_write(ino, len as i64, &[2]).unwrap();
+    assert_eq!(fs.pending_chunks(ino), 1);
+    assert_eq!(fs.flush_data(ino), Ok(1));
+
+    fs.do_write(ino, MIN_CHUNK_SIZE as i64, &[3]).unwrap();
+    assert_eq!(fs.pending_chunks(ino), 3);
+    assert_eq!(fs.flush_data(ino), Ok(3));
+}
+
+#[test]
+fn flushes_resend_only_from_the_first_changed_chunk() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    let tail = MIN_CHUNK_SIZE / 2;
+    let len = 2 * MIN_CHUNK_SIZE + tail;
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, &vec![1; len]).unwrap();
+    fs.do_flush(ino).unwrap();
+    let before = fs.chunk_table[&ino].clone();
+    assert_eq!(fs.backend().uploaded_bytes(), len);
+
+    // Changing the last chunk resends just that one, under a new id
+    fs.do_write(ino, (len - 10) as i64, &[2; 10]).unwrap();
+    fs.do_flush(ino).unwrap();
+    assert_eq!(fs.chunk_table[&ino][..2], before[..2]);
+    assert_ne!(fs.chunk_table[&ino][2], before[2]);
+    assert!(fs.backend().get_chunk(before[2]).is_err());
+    assert_eq!(fs.backend().uploaded_bytes(), len + tail);
+
+    // Changing the middle one resends it and everything after it
+    fs.do_write(ino, MIN_CHUNK_SIZE as i64 + 1, &[3]).unwrap();
+    fs.do_flush(ino).unwrap();
+    assert_eq!(fs.chunk_table[&ino][0], before[0]);
+    assert_ne!(fs.chunk_table[&ino][1], before[1]);
+    assert_eq!(
+        fs.backend().uploaded_bytes(),
+        len + tail + MIN_CHUNK_SIZE + tail
+    );
+
+    fs.data_table.clear();
+    let data = fs.do_read(0, ino, 0, len as u32).unwrap();
+    assert_eq!(
+        (data[0], data[MIN_CHUNK_SIZE + 1], data[len - 1]),
+        (1, 3, 2)
+    );
+}
+
+#[test]
+fn each_open_gets_its_own_handle() {
+    let mut fs = FS::new_for_test();
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+
+    let first = fs.do_open(Caller::mounter(), ino, libc::O_RDONLY).unwrap();
+    let second = fs.do_open(Caller::mounter(), ino, libc::O_RDONLY).unwrap();
+    assert_ne!(first, second);
+
+    // The buffer stays until the last handle is closed
+    fs.do_release(ino, first).unwrap();
+    assert!(fs.data_table.contains_key(&ino));
+    fs.do_release(ino, second).unwrap();
+    assert!(!fs.data_table.contains_key(&ino));
+
+    // Handles aren't reused
+    assert!(fs.do_open(Caller::mounter(), ino, libc::O_RDONLY).unwrap() > second);
+}
+
+#[test]
+fn closing_a_file_drops_its_owners_locks() {
+    let mut fs = FS::new_for_test();
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+    let lock = |owner, typ| lock::Lock {
+        owner,
+        start: 0,
+        end: u64::MAX,
+        typ,
+        pid: 1,
+    };
+
+    fs.do_setlk(ino, lock(1, libc::F_WRLCK)).unwrap();
+    assert_eq!(fs.do_setlk(ino, lock(2, libc::F_RDLCK)), Err(EAGAIN));
+    assert_eq!(
+        fs.do_getlk(ino, lock(2, libc::F_RDLCK)),
+        lock(1, libc::F_WRLCK)
+    );
+
+    fs.release_locks(ino, 1);
+    assert_eq!(fs.do_getlk(ino, lock(2, libc::F_RDLCK)).typ, F_UNLCK);
+    fs.do_setlk(ino, lock(2, libc::F_RDLCK)).unwrap();
+}
+
+#[test]
+fn sequential_reads_prefetch_the_next_chunks() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    fs.readahead = 2;
+    let data: Vec<u8> = (0..7 * MIN_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, &data).unwrap();
+    fs.do_release(ino, 0).unwrap();
+    fs.data_table.clear();
+    let chunks = fs.chunk_table[&ino].clone();
+
+    let gets = |fs: &FS<TestBackend>| {
+        let mut ids: Vec<_> = fs
+            .backend()
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Get { id } => Some(id),
+                _ => None,
+            })
+            .collect();
+        ids.sort();
+        ids
+    };
+
+    // The chunks ahead come in the same batch as the one being read
+    let fh = fs.do_open(Caller::mounter(), ino, libc::O_RDONLY).unwrap();
+    fs.backend().set_latency(Duration::from_millis(100));
+    let started = Instant::now();
+    assert_eq!(fs.do_read(fh, ino, 0, 10), Ok(data[..10].to_vec()));
+    assert!(started.elapsed() < Duration::from_millis(250));
+    fs.backend().set_latency(Duration::ZERO);
+
+    let mut expected = chunks[..3].to_vec();
+    expected.sort();
+    assert_eq!(gets(&fs), expected);
+
+    // Carrying on skips what's already there, and a prefetch that fails doesn't fail
+    // the read that set it off
+    fs.backend().lose_chunk(chunks[3]);
+    let rest = MIN_CHUNK_SIZE - 10;
+    assert_eq!(
+        fs.do_read(fh, ino, 10, rest as u32),
+        Ok(data[10..MIN_CHUNK_SIZE].to_vec())
+    );
+    assert_eq!(gets(&fs).len(), 3);
+    let next = MIN_CHUNK_SIZE;
+    assert_eq!(
+        fs.do_read(fh, ino, next as i64, 10),
+        Ok(data[next..next + 10].to_vec())
+    );
+    assert_eq!(gets(&fs).len(), 4);
+
+    // Jumping around doesn't prefetch, and neither does reading without a
+    // handle, both only fetch the part they read
+    fs.do_read(fh, ino, 4 * MIN_CHUNK_SIZE as i64, 10).unwrap();
+    fs.do_read(0, ino, 5 * MIN_CHUNK_SIZE as i64, 10).unwrap();
+    assert_eq!(gets(&fs).len(), 4);
+    let calls = fs.backend().calls().into_iter();
+    let ranges = calls.filter(|call| matches!(call, Call::GetRange { .. }));
+    assert_eq!(ranges.count(), 2);
+}
+
+#[test]
+fn names_that_arent_utf8_can_be_created_listed_and_unlinked() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut fs = FS::new_for_test();
+    let name = OsStr::from_bytes(b"caf\xe9.txt");
+
+    let ino = fs.do_create(Caller::mounter(), name, 0o644, 0).unwrap().ino;
+    assert_eq!(fs.do_lookup(1, name).unwrap().ino, ino);
+    assert!(fs
+        .do_readdir(1)
+        .unwrap()
+        .contains(&(ino, FileType::RegularFile, name.to_os_string())));
+
+    fs.do_unlink(name).unwrap();
+    assert_eq!(fs.do_lookup(1, name), Err(ENOENT));
+}
+
+#[test]
+fn chunks_that_dont_match_their_checksum_fail_the_read() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("file.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, b"hello").unwrap();
+    fs.do_release(ino, 0).unwrap();
+    let id = fs.chunk_table[&ino][0];
+
+    fs.backend.corrupt_chunk(id, b"jello");
+    assert_eq!(fs.do_read(0, ino, 0, 5), Err(libc::EIO));
+    assert!(!fs.chunk_cache.contains_key(&id));
+
+    fs.verify = false;
+    assert_eq!(fs.do_read(0, ino, 0, 5), Ok(b"jello".to_vec()));
+}
+
+#[test]
+fn a_private_file_is_only_readable_by_its_owner() {
+    let mut fs = FS::new_for_test();
+    let owner = Caller {
+        uid: 1000,
+        gid: 1000,
+    };
+    let other = Caller {
+        uid: 1001,
+        gid: 1001,
+    };
+
+    // Made writable for everyone, so the owner can create in it
+    fs.do_setattr(
+        ROOT_INO,
+        AttrChanges {
+            mode: Some(0o777),
+            ..AttrChanges::default()
+        },
+    )
+    .unwrap();
+
+    let attr = fs
+        .do_create(owner, OsStr::new("secret"), 0o666, 0o066)
+        .unwrap();
+    assert_eq!((attr.uid, attr.gid, attr.perm), (1000, 1000, 0o600));
+    assert_eq!(fs.do_getattr(attr.ino).unwrap().uid, 1000);
+
+    assert_eq!(fs.do_access(owner, attr.ino, R_OK | W_OK), Ok(()));
+    assert!(fs.do_open(owner, attr.ino, libc::O_RDWR).is_ok());
+
+    assert_eq!(fs.do_access(other, attr.ino, R_OK), Err(EACCES));
+    assert_eq!(fs.do_access(other, attr.ino, F_OK), Ok(()));
+    assert_eq!(fs.do_open(other, attr.ino, libc::O_RDONLY), Err(EACCES));
+    assert_eq!(
+        fs.do_open(other, attr.ino, libc::O_RDONLY | O_TRUNC),
+        Err(EACCES)
+    );
+
+    // root is let through, the kernel does the same
+    let root = Caller { uid: 0, gid: 0 };
+    assert!(fs.do_open(root, attr.ino, libc::O_RDONLY).is_ok());
+
+    // Without write access to the root, nothing can be created in it
+    fs.do_setattr(
+        ROOT_INO,
+        AttrChanges {
+            mode: Some(0o755),
+            ..AttrChanges::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        fs.do_create(other, OsStr::new("mine"), 0o644, 0)
+            .map(|attr| attr.ino),
+        Err(EACCES)
+    );
+}
+
+#[test]
+fn an_existing_name_is_never_replaced() {
+    let mut fs = FS::new_for_test();
+    let (ino, _) = fs.add_file("notes.txt", b"first").unwrap();
+    fs.do_flush(ino).unwrap();
+
+    assert_eq!(fs.add_file("notes.txt", b"second"), Err(EEXIST));
+    assert_eq!(
+        fs.do_create(Caller::mounter(), OsStr::new("notes.txt"), 0o644, 0)
+            .map(|attr| attr.ino),
+        Err(EEXIST)
+    );
+
+    assert_eq!(
+        fs.do_lookup(ROOT_INO, OsStr::new("notes.txt")).unwrap().ino,
+        ino
+    );
+    assert_eq!(fs.do_read(0, ino, 0, 100), Ok(b"first".to_vec()));
+    assert_eq!(fs.total_size, 5);
+}
+
+#[test]
+fn append_only_files_only_grow_at_the_end() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("log"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, b"first\n").unwrap();
+    fs.do_set_flags(ino, FS_APPEND_FL).unwrap();
+
+    assert_eq!(fs.do_write(ino, 6, b"second\n"), Ok(7));
+    assert_eq!(fs.do_write(ino, 0, b"FIRST"), Err(EPERM));
+    assert_eq!(fs.do_write(ino, 20, b"gap"), Err(EPERM));
+
+    let shrink = AttrChanges {
+        size: Some(5),
+        ..AttrChanges::default()
+    };
+    assert_eq!(fs.do_setattr(ino, shrink), Err(EPERM));
+    assert_eq!(
+        fs.do_open(Caller::mounter(), ino, libc::O_WRONLY | O_TRUNC),
+        Err(EPERM)
+    );
+    assert_eq!(fs.do_read(0, ino, 0, 100), Ok(b"first\nsecond\n".to_vec()));
+
+    // Once cleared, the file can be rewritten again
+    assert_eq!(fs.do_set_flags(ino, 0x10), Err(EOPNOTSUPP));
+    fs.do_set_flags(ino, 0).unwrap();
+    assert_eq!(fs.do_write(ino, 0, b"FIRST"), Ok(5));
+}
+
+#[test]
+fn directories_cant_be_read_or_written() {
+    let mut fs = FS::new_for_test();
+
+    assert_eq!(fs.do_read(0, ROOT_INO, 0, 10), Err(EISDIR));
+    assert_eq!(fs.do_write(ROOT_INO, 0, b"data"), Err(EISDIR));
+    assert_eq!(fs.do_read(0, 42, 0, 10), Err(ENOENT));
+    assert_eq!(fs.do_write(42, 0, b"data"), Err(ENOENT));
+}
+
+#[test]
+fn regular_files_arent_directories() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("file.txt"), 0o644, 0)
+        .unwrap()
+        .ino;
+
+    assert_eq!(fs.do_lookup(ino, OsStr::new("inner")), Err(ENOTDIR));
+    assert_eq!(fs.do_lookup(ino + 1, OsStr::new("inner")), Err(ENOENT));
+    assert_eq!(fs.do_readdir(ino + 1), Err(ENOENT));
+}
+
+#[test]
+fn opens_beyond_the_limit_fail_until_a_handle_is_released() {
+    let mut fs = FS::new_for_test();
+    fs.max_open_files = Some(2);
+    let (ino, _) = fs.add_file("hello.txt", b"Hello, World!").unwrap();
+
+    let first = fs.do_open(Caller::mounter(), ino, libc::O_RDONLY).unwrap();
+    fs.do_open(Caller::mounter(), ino, libc::O_RDONLY).unwrap();
+    assert_eq!(
+        fs.do_open(Caller::mounter(), ino, libc::O_RDONLY),
+        Err(libc::EMFILE)
+    );
+    assert_eq!(fs.check_handle_limit(), Err(libc::EMFILE));
+
+    fs.do_release(ino, first).unwrap();
+    assert!(fs.do_open(Caller::mounter(), ino, libc::O_RDONLY).is_ok());
+}
+
+#[test]
+fn missing_names_are_remembered_until_created_or_expired() {
+    let mut fs = FS::new_for_test();
+    let name = OsStr::new("later.txt");
+    let key = (ROOT_INO, "later.txt".to_string());
+
+    assert_eq!(fs.do_lookup(ROOT_INO, name), Err(ENOENT));
+    assert!(fs.negative_lookups.contains_key(&key));
+
+    // Creating the file forgets the miss straight away
+    let ino = fs.do_create(Caller::mounter(), name, 0o644, 0).unwrap().ino;
+    assert!(!fs.negative_lookups.contains_key(&key));
+    assert_eq!(fs.do_lookup(ROOT_INO, name).unwrap().ino, ino);
+
+    // A name that shows up behind the cache's back is found once the miss expires
+    let (other, _) = fs.add_file("other.txt", b"").unwrap();
+    let key = (ROOT_INO, "other.txt".to_string());
+    fs.negative_lookups.insert(key.clone(), Instant::now());
+    assert_eq!(fs.do_lookup(ROOT_INO, OsStr::new("other.txt")), Err(ENOENT));
+    fs.negative_lookups
+        .insert(key, Instant::now() - NEGATIVE_LOOKUP_TTL);
+    assert_eq!(
+        fs.do_lookup(ROOT_INO, OsStr::new("other.txt")).unwrap().ino,
+        other
+    );
+
+    // Expired misses are swept once there are enough of them
+    for i in 0..NEGATIVE_LOOKUP_LIMIT {
+        let key = (ROOT_INO, format!("{}.txt", i));
+        fs.negative_lookups
+            .insert(key, Instant::now() - NEGATIVE_LOOKUP_TTL);
+    }
+
+    assert_eq!(fs.do_lookup(ROOT_INO, OsStr::new("gone.txt")), Err(ENOENT));
+    assert_eq!(fs.negative_lookups.len(), 1);
+}
+
+#[test]
+fn new_files_get_their_mode_without_the_umask() {
+    let mut fs = FS::new_for_test();
+
+    let attr = fs
+        .do_create(Caller::mounter(), OsStr::new("a.txt"), 0o666, 0o022)
+        .unwrap();
+    assert_eq!(attr.perm, 0o644);
+    assert_eq!(fs.do_lookup(1, OsStr::new("a.txt")).unwrap().perm, 0o644);
+
+    // Only the permission bits are kept, the file type comes from elsewhere
+    let attr = fs
+        .do_create(
+            Caller::mounter(),
+            OsStr::new("b.sh"),
+            libc::S_IFREG | 0o4777,
+            0o077,
+        )
+        .unwrap();
+    assert_eq!(attr.perm, 0o4700);
+}
+
+#[test]
+fn the_root_belongs_to_whoever_mounts_it() {
+    let mut fs = FS::new_for_test();
+    let root = fs.lookup_table.get_mut(".").unwrap();
+    root.uid = 12345;
+    root.gid = 12345;
+
+    fs.configure_root(None);
+    let root = *fs.get_attr(ROOT_INO).unwrap();
+    assert_eq!((root.uid, root.gid), unsafe {
+        (libc::getuid(), libc::getgid())
+    });
+    assert_eq!(root.perm, DEFAULT_ROOT_MODE);
+
+    fs.configure_root(Some(0o700));
+    assert_eq!(fs.get_attr(ROOT_INO).unwrap().perm, 0o700);
+}
+
+#[test]
+fn modes_are_octal() {
+    assert_eq!(parse_mode("0755"), Ok(0o755));
+    assert_eq!(parse_mode("700"), Ok(0o700));
+    assert_eq!(parse_mode("0o1777"), Ok(0o1777));
+    assert!(parse_mode("0789").is_err());
+    assert!(parse_mode("17777").is_err());
+}
+
+#[test]
+fn directory_size_follows_its_entries_not_their_data() {
+    let mut fs = FS::new_for_test();
+
+    for name in ["a", "b", "c"] {
+        fs.add_file(name, &[7; 10_000]).unwrap();
+    }
+
+    let root = fs.do_getattr(ROOT_INO).unwrap();
+    assert_ne!(root.size, 30_000);
+    assert_eq!(root.size, 5 * DIRENT_SIZE);
+    assert_eq!(root.blocks, 1);
+    assert_eq!(fs.total_size, 30_000);
+
+    fs.do_unlink(OsStr::new("a")).unwrap();
+    assert_eq!(fs.do_getattr(ROOT_INO).unwrap().size, 4 * DIRENT_SIZE);
+}
+
+#[test]
+fn blocks_follow_the_size() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("file"), 0o644, 0)
+        .unwrap()
+        .ino;
+    let blocks = |fs: &FS<TestBackend>| fs.get_attr(ino).unwrap().blocks;
+    assert_eq!(blocks(&fs), 0);
+
+    fs.do_write(ino, 0, &[1; 512]).unwrap();
+    assert_eq!(blocks(&fs), 1);
+    fs.do_write(ino, 512, &[1]).unwrap();
+    assert_eq!(blocks(&fs), 2);
+
+    fs.do_fallocate(ino, 0, 4096, 0).unwrap();
+    assert_eq!(blocks(&fs), 8);
+
+    let changes = AttrChanges {
+        size: Some(0),
+        ..Default::default()
+    };
+    fs.do_setattr(ino, changes).unwrap();
+    assert_eq!(blocks(&fs), 0);
+}
+
+#[test]
+fn reads_of_deleted_chunks_fail_fast_and_the_rest_is_still_served() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    fs.readahead = 0;
+    let data: Vec<u8> = (0..2 * MIN_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, &data).unwrap();
+    fs.do_release(ino, 0).unwrap();
+    fs.data_table.clear();
+    let chunks = fs.chunk_table[&ino].clone();
+
+    fs.backend().lose_chunk(chunks[0]);
+    let gets = |fs: &FS<TestBackend>| {
+        fs.backend()
+            .calls()
+            .iter()
+            .filter(|call| matches!(call, Call::Get { .. }))
+            .count()
+    };
+
+    assert_eq!(fs.do_read(0, ino, 0, 10), Err(libc::EIO));
+    assert!(fs.missing_chunks.contains(&chunks[0]));
+    let asked = gets(&fs);
+
+    // The backend isn't asked about the lost chunk again
+    assert_eq!(fs.do_read(0, ino, 0, 10), Err(libc::EIO));
+    assert_eq!(gets(&fs), asked);
+
+    let start = MIN_CHUNK_SIZE;
+    assert_eq!(
+        fs.do_read(0, ino, start as i64, 10),
+        Ok(data[start..start + 10].to_vec())
+    );
+}
+
+#[test]
+fn refreshes_pick_up_files_other_mounts_saved() {
+    let mut fs = FS::new_for_test();
+    fs.save_index();
+
+    // Another mount adds a file to the shared index
+    let mut index = Index::fetch(fs.backend(), None).unwrap().unwrap();
+    let remote = FileAttr {
+        ino: 50,
+        kind: FileType::RegularFile,
+        ..root_attr(0o644)
+    };
+    index.lookup_table.insert("remote.txt".to_string(), remote);
+    index.path_table.insert(50, "remote.txt".to_string());
+    index.chunk_table.insert(50, Vec::new());
+    index.last_inode = 50;
+    fs.backend().save_index(&index.to_bytes().unwrap()).unwrap();
+
+    let name = OsStr::new("remote.txt");
+    fs.refresh_interval = Some(Duration::from_secs(3600));
+    fs.maybe_refresh();
+    assert_eq!(fs.do_lookup(ROOT_INO, name), Err(ENOENT));
+
+    fs.refresh_interval = Some(Duration::ZERO);
+    fs.maybe_refresh();
+    assert_eq!(fs.do_lookup(ROOT_INO, name).unwrap().ino, 50);
+    assert!(fs.allocate_inode() > 50);
+}
+
+#[test]
+fn writes_past_the_dirty_limit_flush_the_biggest_file() {
+    let mut fs = FS::new_for_test();
+    let small = fs
+        .do_create(Caller::mounter(), OsStr::new("small"), 0o644, 0)
+        .unwrap()
+        .ino;
+    let big = fs
+        .do_create(Caller::mounter(), OsStr::new("big"), 0o644, 0)
+        .unwrap()
+        .ino;
+
+    // Only what changed since the last flush counts
+    fs.do_write(big, 0, &[1; 1000]).unwrap();
+    fs.do_flush(big).unwrap();
+    fs.do_write(big, 900, &[2; 100]).unwrap();
+    assert_eq!(fs.dirty_bytes(big), 100);
+    assert_eq!(fs.metrics.dirty_bytes.load(Ordering::Relaxed), 100);
+
+    fs.max_dirty = Some(150);
+    fs.do_write(small, 0, &[3; 40]).unwrap();
+    assert!(fs.dirty.contains(&big));
+
+    fs.do_write(big, 1000, &[4; 50]).unwrap();
+    assert!(!fs.dirty.contains(&big));
+    assert!(fs.dirty.contains(&small));
+    assert_eq!(fs.total_dirty_bytes(), 40);
+    assert_eq!(fs.metrics.backpressure_flushes.load(Ordering::Relaxed), 1);
+
+    // The data went through all the same
+    fs.data_table.remove(&big);
+    assert_eq!(
+        fs.do_read(0, big, 995, 10),
+        Ok(vec![2, 2, 2, 2, 2, 4, 4, 4, 4, 4])
+    );
+}
+
+#[test]
+fn opening_with_o_trunc_empties_the_file_without_fetching_it() {
+    let mut fs = FS::new_for_test();
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("file"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, b"Hello, World!").unwrap();
+    fs.do_release(ino, 0).unwrap();
+    let old = fs.chunk_table[&ino].clone();
+    let calls = fs.backend().calls().len();
+
+    let fh = fs
+        .do_open(Caller::mounter(), ino, libc::O_WRONLY | libc::O_TRUNC)
+        .unwrap();
+    assert_eq!(fs.get_attr(ino).unwrap().size, 0);
+    assert_eq!(fs.total_size, 0);
+    assert_eq!(fs.backend().calls().len(), calls);
+
+    // The old chunks are dropped once the change is flushed
+    fs.do_release(ino, fh).unwrap();
+    assert!(fs.chunk_table[&ino].iter().all(|id| !old.contains(id)));
+    assert_eq!(fs.do_read(0, ino, 0, 13), Ok(Vec::new()));
+
+    fs.read_only = true;
+    assert_eq!(
+        fs.do_open(Caller::mounter(), ino, libc::O_WRONLY | libc::O_TRUNC),
+        Err(EROFS)
+    );
+}
+
+#[test]
+fn added_files_arent_executable() {
+    let mut fs = FS::new_for_test();
+    let (_, attr) = fs.add_file("sample.txt", b"sample").unwrap();
+    assert_eq!(attr.perm, DEFAULT_FILE_MODE);
+    assert_eq!(attr.perm & 0o111, 0);
+    assert_eq!(fs.get_attr(ROOT_INO).unwrap().perm, DEFAULT_ROOT_MODE);
+}
+
+#[test]
+fn flushes_report_how_many_chunks_they_wrote() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("big.bin"), 0o644, 0)
+        .unwrap()
+        .ino;
+
+    fs.do_write(ino, 0, &vec![1; 3 * MIN_CHUNK_SIZE]).unwrap();
+    assert_eq!(fs.flush_data(ino), Ok(3));
+    assert_eq!(fs.flush_data(ino), Ok(0));
+
+    // Only the chunks from the first change on are written again
+    fs.do_write(ino, MIN_CHUNK_SIZE as i64 + 1, &[2]).unwrap();
+    assert_eq!(fs.flush_data(ino), Ok(2));
+}
+
+#[test]
+fn saves_merge_in_what_another_mount_saved_meanwhile() {
+    let mut fs = FS::new_for_test();
+    fs.do_create(Caller::mounter(), OsStr::new("local.txt"), 0o644, 0)
+        .unwrap();
+
+    // Another mount of the same store adds a file and saves
+    let mut index = Index::fetch(fs.backend(), None).unwrap().unwrap();
+    let remote = FileAttr {
+        ino: 50,
+        kind: FileType::RegularFile,
+        ..root_attr(0o644)
+    };
+    index.lookup_table.insert("remote.txt".to_string(), remote);
+    index.path_table.insert(50, "remote.txt".to_string());
+    index.chunk_table.insert(50, Vec::new());
+    index.last_inode = 50;
+    index.write(fs.backend(), None).unwrap();
+
+    // Our next save keeps both instead of overwriting theirs
+    fs.do_create(Caller::mounter(), OsStr::new("later.txt"), 0o644, 0)
+        .unwrap();
+    assert_eq!(fs.allocate_inode(), 51);
+
+    let saved = Index::fetch(fs.backend(), None).unwrap().unwrap();
+    assert!(saved.version > index.version);
+    for name in ["local.txt", "remote.txt", "later.txt"] {
+        assert!(saved.lookup_table.contains_key(name), "{} is missing", name);
+    }
+}
+
+#[test]
+fn files_two_mounts_create_under_the_same_inode_are_both_kept() {
+    let dir = testing::temp_dir();
+    let mut one = FS::new(DirBackend::open(dir.clone()).unwrap());
+    one.save_index();
+    let mut other = FS::new(DirBackend::open(dir.clone()).unwrap());
+    other.restore_index(Index::fetch(&other.backend, None).unwrap().unwrap());
+
+    let create = |fs: &mut FS<DirBackend>, name: &str, data: &[u8]| {
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new(name), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, data).unwrap();
+        fs.do_release(ino, 0).unwrap();
+        ino
+    };
+
+    // Neither has seen the other's file when picking the next inode
+    let ours = create(&mut one, "ours.txt", b"ours");
+    let theirs = create(&mut other, "theirs.txt", b"theirs");
+    assert_eq!(ours, theirs);
+
+    // A fresh mount finds both files, each with its own inode and data
+    let mut fs = FS::new(DirBackend::open(dir).unwrap());
+    fs.restore_index(Index::fetch(&fs.backend, None).unwrap().unwrap());
+    let ours = fs.do_lookup(ROOT_INO, OsStr::new("ours.txt")).unwrap();
+    let theirs = fs.do_lookup(ROOT_INO, OsStr::new("theirs.txt")).unwrap();
+    assert_ne!(ours.ino, theirs.ino);
+    assert_eq!(fs.do_read(0, ours.ino, 0, 100), Ok(b"ours".to_vec()));
+    assert_eq!(fs.do_read(0, theirs.ino, 0, 100), Ok(b"theirs".to_vec()));
+    assert_eq!(fs.allocate_inode(), ours.ino.max(theirs.ino) + 1);
+}
+
+#[test]
+fn saves_never_replace_an_index_they_did_not_merge() {
+    let mut fs = FS::new_for_test();
+    fs.save_index();
+    let stored = fs.backend().load_index().unwrap();
+
+    // Another mount saved after ours was read, so ours is refused
+    let mut newer = Index::fetch(fs.backend(), None).unwrap().unwrap();
+    newer.write(fs.backend(), None).unwrap();
+    let index = fs.to_index(99).to_bytes().unwrap();
+    assert!(!fs
+        .backend()
+        .save_index_if(stored.as_deref(), &index)
+        .unwrap());
+
+    let saved = Index::fetch(fs.backend(), None).unwrap().unwrap();
+    assert_eq!(saved.version, newer.version);
+}
+
+#[test]
+fn small_sequential_writes_upload_once_per_chunk() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("log"), 0o644, 0)
+        .unwrap()
+        .ino;
+    let block = [7; 4096];
+
+    for i in 0..2048 {
+        fs.do_write(ino, i * 4096, &block).unwrap();
+    }
+
+    let puts = |fs: &FS<TestBackend>| {
+        let calls = fs.backend().calls();
+        calls
+            .iter()
+            .filter(|call| matches!(call, Call::Put { .. }))
+            .count()
+    };
+
+    assert_eq!(puts(&fs), 0);
+    fs.do_flush(ino).unwrap();
+    assert_eq!(puts(&fs), 2048 * 4096 / MIN_CHUNK_SIZE);
+    assert_eq!(fs.backend().uploaded_bytes(), 2048 * 4096);
+}
+
+#[test]
+fn checkpoints_save_the_index_and_only_then_delete_replaced_chunks() {
+    let mut fs = FS::new_for_test();
+    fs.chunk_size = MIN_CHUNK_SIZE;
+    fs.checkpoint_interval = Some(Duration::from_secs(3600));
+    let saves = |fs: &FS<TestBackend>| {
+        let calls = fs.backend().calls();
+        calls
+            .iter()
+            .filter(|call| matches!(call, Call::SaveIndex { .. }))
+            .count()
+    };
+
+    let ino = fs
+        .do_create(Caller::mounter(), OsStr::new("file"), 0o644, 0)
+        .unwrap()
+        .ino;
+    fs.do_write(ino, 0, &vec![1; 2 * MIN_CHUNK_SIZE]).unwrap();
+    fs.do_flush(ino).unwrap();
+    assert_eq!(saves(&fs), 0);
+    assert!(fs.index_dirty);
+
+    fs.checkpoint();
+    assert_eq!(saves(&fs), 1);
+    fs.checkpoint();
+    assert_eq!(saves(&fs), 1);
+
+    // The saved index still points at the old chunks until the next checkpoint
+    let old = fs.chunk_table[&ino].clone();
+    fs.do_write(ino, 0, &[2]).unwrap();
+    fs.do_flush(ino).unwrap();
+    assert_eq!(fs.unsaved_deletes, old);
+    assert!(old.iter().all(|id| fs.backend().get_chunk(*id).is_ok()));
+
+    fs.checkpoint();
+    assert!(fs.unsaved_deletes.is_empty());
+    assert!(old.iter().all(|id| fs.backend().get_chunk(*id).is_err()));
+}
@@ -0,0 +1,185 @@
+use crate::backend::{ChunkId, StorageBackend};
+use std::io;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// Token bucket holding up to a second's worth of bytes. Transfers larger than
+// what's available drive it into debt, which later callers wait off.
+pub struct TokenBucket {
+    rate: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub fn take(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+
+            let now = Instant::now();
+            let refill = now.duration_since(*last).as_secs_f64() * self.rate as f64;
+
+            *tokens = (*tokens + refill).min(self.rate as f64) - bytes as f64;
+            *last = now;
+
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / self.rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        // Sleep without the lock so other transfers can queue up their debt
+        thread::sleep(wait);
+    }
+}
+
+// Limits how fast the wrapped backend sends and receives data
+pub struct ThrottleBackend<B> {
+    inner: B,
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl<B: StorageBackend> ThrottleBackend<B> {
+    pub fn new(inner: B, upload_rate: Option<u64>, download_rate: Option<u64>) -> Self {
+        ThrottleBackend {
+            inner,
+            upload: upload_rate.map(TokenBucket::new),
+            download: download_rate.map(TokenBucket::new),
+        }
+    }
+
+    fn sent(&self, bytes: usize) {
+        if let Some(bucket) = &self.upload {
+            bucket.take(bytes);
+        }
+    }
+
+    fn received(&self, bytes: usize) {
+        if let Some(bucket) = &self.download {
+            bucket.take(bytes);
+        }
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for ThrottleBackend<B> {
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkId> {
+        self.sent(data.len());
+        self.inner.put_chunk(data)
+    }
+
+    fn get_chunk(&self, id: ChunkId) -> io::Result<Vec<u8>> {
+        let chunk = self.inner.get_chunk(id)?;
+        self.received(chunk.len());
+
+        Ok(chunk)
+    }
+
+    fn delete_chunk(&self, id: ChunkId) -> io::Result<()> {
+        self.inner.delete_chunk(id)
+    }
+
+    fn load_index(&self) -> io::Result<Option<Vec<u8>>> {
+        let index = self.inner.load_index()?;
+        self.received(index.as_ref().map_or(0, Vec::len));
+
+        Ok(index)
+    }
+
+    fn save_index(&self, index: &[u8]) -> io::Result<()> {
+        self.sent(index.len());
+        self.inner.save_index(index)
+    }
+
+    fn save_index_if(&self, expected: Option<&[u8]>, index: &[u8]) -> io::Result<bool> {
+        self.sent(index.len());
+        self.inner.save_index_if(expected, index)
+    }
+
+    fn load_previous_index(&self) -> io::Result<Option<Vec<u8>>> {
+        let index = self.inner.load_previous_index()?;
+        self.received(index.as_ref().map_or(0, Vec::len));
+
+        Ok(index)
+    }
+
+    fn store_id(&self) -> Option<String> {
+        self.inner.store_id()
+    }
+
+    fn list_chunks(&self) -> io::Result<Vec<(ChunkId, SystemTime)>> {
+        self.inner.list_chunks()
+    }
+
+    fn get_chunk_range(&self, id: ChunkId, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let data = self.inner.get_chunk_range(id, range)?;
+        self.received(data.len());
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemBackend;
+
+    #[test]
+    fn transfers_are_held_to_the_rate() {
+        let backend = ThrottleBackend::new(MemBackend::default(), Some(1000), Some(1000));
+        let start = Instant::now();
+
+        // The first second's worth passes straight away, the rest takes 0.5s
+        let ids: Vec<ChunkId> = (0..3)
+            .map(|_| backend.put_chunk(&[0; 500]).unwrap())
+            .collect();
+        assert!(start.elapsed() >= Duration::from_millis(450));
+
+        let start = Instant::now();
+
+        for id in ids {
+            backend.get_chunk(id).unwrap();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(450));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn concurrent_transfers_share_one_budget() {
+        let backend = ThrottleBackend::new(MemBackend::default(), Some(1000), None);
+        let start = Instant::now();
+
+        // Four at once go no faster than four in a row would
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| backend.put_chunk(&[0; 500]).unwrap());
+            }
+        });
+
+        assert!(start.elapsed() >= Duration::from_millis(950));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn no_limit_means_no_waiting() {
+        let backend = ThrottleBackend::new(MemBackend::default(), None, None);
+        let start = Instant::now();
+
+        for _ in 0..100 {
+            backend.put_chunk(&[0; 100_000]).unwrap();
+        }
+
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::backend::StorageBackend;
+use crate::index::Index;
+use std::path::Path;
+use std::process;
+
+// Deletes the chunks of everything unlinked while mounted with --trash
+pub fn empty<B: StorageBackend + ?Sized>(backend: &B, index_path: Option<&Path>) {
+    let Some(mut index) = Index::fetch(backend, index_path).unwrap() else {
+        eprintln!("no index found");
+        process::exit(1);
+    };
+
+    let trash = std::mem::take(&mut index.trash);
+
+    for (ino, (name, _)) in &trash {
+        let chunks = index.chunk_table.remove(ino).unwrap_or_default();
+        index.chunk_sizes.remove(ino);
+
+        for id in &chunks {
+            index.checksums.remove(id);
+
+            if let Err(e) = backend.delete_chunk(*id) {
+                eprintln!("failed to delete chunk {} of {}: {}", id, name, e);
+            }
+        }
+
+        index.free_inodes.push(*ino);
+        println!("purged {} ({} chunk(s))", name, chunks.len());
+    }
+
+    println!("{} file(s) purged", trash.len());
+
+    if trash.is_empty() {
+        return;
+    }
+
+    index.write(backend, index_path).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fsck, Caller, FS};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn unlinked_files_stay_until_the_trash_is_emptied() {
+        let mut fs = FS::new_for_test();
+        fs.use_trash = true;
+
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new("doomed.txt"), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, b"contents").unwrap();
+        fs.do_release(ino, 0).unwrap();
+        let id = fs.chunk_table[&ino][0];
+
+        fs.do_unlink(OsStr::new("doomed.txt")).unwrap();
+        fs.do_forget(ino, 1);
+        assert!(fs.backend.get_chunk(id).is_ok());
+        assert_eq!(fs.total_size, 8);
+
+        // Trashed chunks aren't orphans
+        let mut index = Index::fetch(&fs.backend, None).unwrap().unwrap();
+        assert!(fsck::check(&fs.backend, &mut index, false, false)
+            .problems
+            .is_empty());
+
+        empty(&fs.backend, None);
+        assert!(fs.backend.get_chunk(id).is_err());
+
+        let index = Index::fetch(&fs.backend, None).unwrap().unwrap();
+        assert!(index.trash.is_empty());
+        assert_eq!(index.free_inodes, vec![ino]);
+    }
+
+    #[test]
+    fn unflushed_writes_are_sent_before_going_to_the_trash() {
+        let mut fs = FS::new_for_test();
+        fs.use_trash = true;
+
+        let ino = fs
+            .do_create(Caller::mounter(), OsStr::new("fresh.txt"), 0o644, 0)
+            .unwrap()
+            .ino;
+        fs.do_write(ino, 0, b"never flushed").unwrap();
+
+        // The index saved by the unlink has to stand on its own, the mount
+        // might not get to release the file
+        fs.do_unlink(OsStr::new("fresh.txt")).unwrap();
+
+        let index = Index::fetch(&fs.backend, None).unwrap().unwrap();
+        assert!(index.trash.contains_key(&ino));
+        let id = index.chunk_table[&ino][0];
+        assert_eq!(fs.backend.get_chunk(id).unwrap(), b"never flushed");
+    }
+}